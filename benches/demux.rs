@@ -0,0 +1,106 @@
+/*
+ * Copyright (c) 2022 Cisco and/or its affiliates.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at:
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use bytes::{BufMut, BytesMut};
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use msm_rtsp_stub::demux::{ClientCodec, ClientDemux, ClientMessage};
+
+use tokio_util::codec::Decoder;
+
+/// typical RTP packet size for a high bitrate camera feed
+const FRAME_SIZE: usize = 1400;
+const FRAME_COUNT: usize = 1000;
+
+/// client socket read size
+const READ_SIZE: usize = 4096;
+
+const RTSP_REQUEST: &[u8] = b"GET_PARAMETER rtsp://camera/stream RTSP/1.0\r\nCSeq: 7\r\nSession: 12345678\r\n\r\n";
+
+fn interleaved(stream: &mut BytesMut, channel: u8, size: usize) {
+    stream.put_u8(b'$');
+    stream.put_u8(channel);
+    stream.put_u16(size as u16);
+    stream.put_bytes(0x5a, size);
+}
+
+/// interleaved RTP and RTCP frames with the odd RTSP keepalive mixed in
+fn client_stream(rtsp: bool) -> BytesMut {
+    let mut stream = BytesMut::new();
+    for i in 0..FRAME_COUNT {
+        if rtsp && i % 100 == 0 {
+            stream.put_slice(RTSP_REQUEST);
+        }
+        if i % 50 == 0 {
+            interleaved(&mut stream, 1, 64);
+        } else {
+            interleaved(&mut stream, 0, FRAME_SIZE);
+        }
+    }
+    return stream
+}
+
+fn decode_all(codec: &mut ClientCodec, buf: &mut BytesMut) -> usize {
+    let mut bytes = 0;
+    while let Some(message) = codec.decode(buf).unwrap() {
+        bytes += match message {
+            ClientMessage::Interleaved { data, .. } => data.len(),
+            ClientMessage::Rtsp(message) => message.len(),
+        };
+    }
+    return bytes
+}
+
+fn bench_frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("demux");
+
+    for (name, rtsp) in [("frames", false), ("mixed", true)] {
+        let stream = client_stream(rtsp);
+        group.throughput(Throughput::Bytes(stream.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter_batched_ref(
+                || stream.clone(),
+                |buf| black_box(decode_all(&mut ClientCodec, buf)),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    let stream = client_stream(true);
+    group.throughput(Throughput::Bytes(stream.len() as u64));
+    group.bench_function("fragmented", |b| {
+        b.iter(|| {
+            let mut demux = ClientDemux::new();
+            let mut bytes = 0;
+            for chunk in stream.chunks(READ_SIZE) {
+                demux.read_buf().put_slice(chunk);
+                while let Some(message) = demux.next_message().unwrap() {
+                    bytes += match message {
+                        ClientMessage::Interleaved { data, .. } => data.len(),
+                        ClientMessage::Rtsp(message) => message.len(),
+                    };
+                }
+            }
+            black_box(bytes)
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_frames);
+criterion_main!(benches);
@@ -0,0 +1,226 @@
+/*
+ * Copyright (c) 2022 Cisco and/or its affiliates.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at:
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::cp::{cp_queue_depth, cp_ready, cp_running};
+use crate::dp::dp_ready;
+use crate::stub::Stub;
+
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+use log::{debug, trace, warn};
+
+use std::convert::Infallible;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+const ADMIN_METRICS_PATH: &str = "/metrics";
+const ADMIN_HEALTH_PATH: &str = "/healthz";
+const ADMIN_READY_PATH: &str = "/readyz";
+const ADMIN_SESSIONS_PATH: &str = "/sessions";
+const ADMIN_METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// plain response with the given status
+fn admin_response(status: StatusCode, body: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    return response
+}
+
+/// 200 if every check passes, otherwise 503, with one line per check
+fn admin_checks(checks: &[(&str, bool)]) -> Response<Body> {
+    let body: String = checks.iter()
+        .map(|(check, ok)| format!("{}: {}\n", check, if *ok { "ok" } else { "failed" }))
+        .collect();
+
+    let mut response = Response::new(Body::from(body));
+    if !checks.iter().all(|(_, ok)| *ok) {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    return response
+}
+
+/// live while the client listener and the CP connector are still running, or they stopped for shutdown
+fn admin_health(stub: &Stub) -> Response<Body> {
+    let stopping = stub.is_shutting_down();
+    return admin_checks(&[
+        ("listener", stub.is_listening() || stopping),
+        ("cp connector", cp_running(stub) || stopping),
+    ])
+}
+
+/// ready once the stub can carry media: registered over a live CP stream with the DP configured
+fn admin_ready(stub: &Stub) -> Response<Body> {
+    return admin_checks(&[
+        ("running", !stub.is_shutting_down()),
+        ("listener", stub.is_listening()),
+        ("cp registered", cp_ready(stub)),
+        ("dp configured", dp_ready(stub)),
+    ])
+}
+
+/// current metrics in the Prometheus text format
+fn admin_metrics(stub: &Stub) -> Response<Body> {
+    stub.metrics.cp_queue_depth.set(cp_queue_depth(stub) as i64);
+
+    match stub.metrics.encode() {
+        Ok(encoded) => {
+            let mut response = Response::new(Body::from(encoded));
+            response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(ADMIN_METRICS_CONTENT_TYPE));
+            return response
+        },
+        Err(e) => {
+            warn!("unable to encode metrics: {}", e);
+            return admin_response(StatusCode::INTERNAL_SERVER_ERROR, "unable to encode metrics\n")
+        },
+    }
+}
+
+/// one line per client flow: its addresses, session state, session id and transports
+fn admin_sessions(stub: &Stub) -> Response<Body> {
+    let mut lines: Vec<String> = stub.sessions().iter()
+        .map(|(flow, session)| format!("{} {}\n", flow, session))
+        .collect();
+    lines.sort();
+    return Response::new(Body::from(lines.concat()))
+}
+
+/// route one admin request
+async fn admin_request(stub: Arc<Stub>, request: Request<Body>) -> std::result::Result<Response<Body>, Infallible> {
+    trace!("admin request {} {}", request.method(), request.uri().path());
+
+    if request.method() != Method::GET {
+        return Ok(admin_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n"))
+    }
+
+    match request.uri().path() {
+        ADMIN_METRICS_PATH => return Ok(admin_metrics(&stub)),
+        ADMIN_HEALTH_PATH => return Ok(admin_health(&stub)),
+        ADMIN_READY_PATH => return Ok(admin_ready(&stub)),
+        ADMIN_SESSIONS_PATH => return Ok(admin_sessions(&stub)),
+        _ => return Ok(admin_response(StatusCode::NOT_FOUND, "not found\n")),
+    }
+}
+
+/// Admin HTTP server with the Prometheus metrics, the health and readiness endpoints and the session list
+/// keeps answering while the stub shuts down, so readiness fails while flows drain
+pub async fn admin_server(stub: Arc<Stub>, address: SocketAddr) -> Result<()> {
+    let make_service = make_service_fn(move |_connection| {
+        let connection_stub = stub.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| admin_request(connection_stub.clone(), request)))
+        }
+    });
+
+    match Server::try_bind(&address) {
+        Ok(builder) => {
+            debug!("admin server listening on {}", address);
+            match builder.serve(make_service).await {
+                Ok(()) => return Ok(()),
+                Err(e) => return Err(Error::other(e.to_string())),
+            }
+        },
+        Err(e) => return Err(Error::new(ErrorKind::AddrNotAvailable, e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dp::{dp_init, DpProxy};
+    use crate::metrics::{MEDIA_RTCP, MEDIA_RTP, TO_CLIENT, TO_DP};
+    use crate::rtsp::RtspMethod;
+
+    /// status, content type and body of a GET through the admin router
+    async fn get(stub: &Arc<Stub>, path: &str) -> (StatusCode, Option<HeaderValue>, String) {
+        let request = Request::get(path).body(Body::empty()).expect("invalid request");
+        let response = match admin_request(stub.clone(), request).await {
+            Ok(response) => response,
+            Err(e) => match e {},
+        };
+        let status = response.status();
+        let content_type = response.headers().get(CONTENT_TYPE).cloned();
+        let body = hyper::body::to_bytes(response.into_body()).await.expect("no body");
+        return (status, content_type, String::from_utf8(body.to_vec()).expect("body not UTF-8"))
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_as_prometheus_text() {
+        let stub = Stub::new(20000);
+        stub.metrics.rtsp_request(&RtspMethod::Setup);
+        stub.metrics.rtsp_request(&RtspMethod::Setup);
+        stub.metrics.media(MEDIA_RTP, TO_DP, 1200);
+        stub.metrics.media(MEDIA_RTCP, TO_CLIENT, 80);
+        // the queue depth is read from the CP queue for each scrape
+        stub.metrics.cp_queue_depth.set(7);
+
+        let (status, content_type, body) = get(&stub, ADMIN_METRICS_PATH).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, Some(HeaderValue::from_static(ADMIN_METRICS_CONTENT_TYPE)));
+        for line in [
+            "msm_rtsp_stub_rtsp_requests_total{method=\"SETUP\"} 2",
+            "msm_rtsp_stub_media_packets_total{direction=\"to_dp\",media=\"rtp\"} 1",
+            "msm_rtsp_stub_media_bytes_total{direction=\"to_dp\",media=\"rtp\"} 1200",
+            "msm_rtsp_stub_media_packets_total{direction=\"to_client\",media=\"rtcp\"} 1",
+            "msm_rtsp_stub_media_bytes_total{direction=\"to_client\",media=\"rtcp\"} 80",
+            "msm_rtsp_stub_cp_queue_depth 0",
+        ] {
+            assert!(body.lines().any(|metric| metric == line), "no {} in\n{}", line, body);
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_and_methods_are_refused() {
+        let stub = Stub::new(20000);
+        assert_eq!(get(&stub, "/other").await.0, StatusCode::NOT_FOUND);
+
+        let request = Request::post(ADMIN_METRICS_PATH).body(Body::empty()).expect("invalid request");
+        match admin_request(stub, request).await {
+            Ok(response) => assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED),
+            Err(e) => match e {},
+        }
+    }
+
+    #[tokio::test]
+    async fn health_fails_when_tasks_stop_outside_shutdown() {
+        let stub = Stub::new(20000);
+        assert_eq!(get(&stub, ADMIN_HEALTH_PATH).await, (StatusCode::SERVICE_UNAVAILABLE, None, "listener: failed\ncp connector: failed\n".to_string()));
+
+        stub.set_listening(true);
+        assert_eq!(get(&stub, ADMIN_HEALTH_PATH).await.0, StatusCode::SERVICE_UNAVAILABLE);
+
+        // tasks stopping is expected once the stub shuts down
+        stub.set_listening(false);
+        stub.shutdown();
+        assert_eq!(get(&stub, ADMIN_HEALTH_PATH).await, (StatusCode::OK, None, "listener: ok\ncp connector: ok\n".to_string()));
+    }
+
+    #[tokio::test]
+    async fn ready_needs_every_check() {
+        let stub = Stub::new(20000);
+        stub.set_listening(true);
+        assert_eq!(get(&stub, ADMIN_READY_PATH).await, (StatusCode::SERVICE_UNAVAILABLE, None, "running: ok\nlistener: ok\ncp registered: failed\ndp configured: failed\n".to_string()));
+
+        // the DP alone isn't enough without REGISTER over a live stream
+        dp_init(&stub, DpProxy::from_rtp(SocketAddr::from(([127, 0, 0, 1], 9)))).await.expect("DP not configured");
+        assert_eq!(get(&stub, ADMIN_READY_PATH).await, (StatusCode::SERVICE_UNAVAILABLE, None, "running: ok\nlistener: ok\ncp registered: failed\ndp configured: ok\n".to_string()));
+
+        stub.shutdown();
+        assert_eq!(get(&stub, ADMIN_READY_PATH).await.2, "running: failed\nlistener: ok\ncp registered: failed\ndp configured: ok\n");
+    }
+}
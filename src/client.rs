@@ -14,156 +14,721 @@
  * limitations under the License.
  */
 
-use crate::cp::cp_add;
-use crate::cp::cp_delete;
-use crate::cp::cp_data;
-use crate::dp::dp_demux;
-use crate::dp::dp_rtp_recv;
-use crate::dp::dp_rtcp_recv;
+use crate::cp::{cp_add, cp_delete, cp_data, cp_reply, FlowHandle, FlowKey};
+use crate::demux::{ClientDemux, ClientMessage};
+use crate::dp::{dp_flow, DpFlow};
+use crate::dp::{dp_allow_client_media, dp_channels, dp_channels_udp, dp_remove_channels, dp_remove_tracks};
+use crate::dp::dp_send;
+use crate::lock::lock;
+use crate::media::sdp_codecs;
+use crate::metrics::{Metrics, MEDIA_RTCP, MEDIA_RTP};
+use crate::queue::{ClientQueue, FrameKind, Queued};
+use crate::rtsp::{RtspMessage, RtspMethod, RtspRequest, RtspResponse, RtspTransport};
+use crate::session::{Keepalive, RtspSession, SessionState};
+use crate::stub::Stub;
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 
-use log::{debug, error, info, trace, warn};
+use log::{debug, error, trace, warn};
 
+use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use tokio::net::{TcpListener, TcpStream};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::sync::mpsc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep, timeout};
+
+use tracing::{field, info_span, Instrument, Span};
 
 const CLIENT_CHANNEL_SIZE: usize = 5;
+const CLIENT_MEDIA_CHANNEL_SIZE: usize = 256;
+
+/// how long a client closed by the CP gets to take what is queued for it
+const CLIENT_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// how often a flow checks its RTSP session timeout, which the server may change
+const CLIENT_SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// slack on top of the session timeout, for clients that send their keepalive right on time
+const CLIENT_SESSION_GRACE: Duration = Duration::from_secs(5);
+
+/// client transport from a SETUP that asked for RTP over UDP, kept until the response arrives
+#[derive(Debug)]
+struct UdpSetup {
+    transport: RtspTransport,
+    rtp_channel: u8,
+    server_port: u16,
+}
+
+/// what a SETUP set up before its response arrived, so it can be finished or undone once it does
+#[derive(Debug, Default)]
+struct PendingSetup {
+    // set when the client asked for RTP over UDP
+    udp: Option<UdpSetup>,
+    // RTP channels of the interleaved pairs newly mapped for the request
+    channels: Vec<u8>,
+}
+
+/// SETUPs awaiting a response, keyed by CSeq
+type PendingSetups = Arc<Mutex<HashMap<u32, PendingSetup>>>;
+
+/// spans of client requests awaiting a response, keyed by CSeq
+/// a request span stays open until its response is written, so it measures the round trip through the CP
+type PendingRequests = Arc<Mutex<HashMap<u32, Span>>>;
+
+/// RTSP session of the flow, fed requests by the reader and responses by the CP response task
+type FlowSession = Arc<Mutex<RtspSession>>;
 
-/// read message from client
-async fn client_read(reader: &OwnedReadHalf, buf: &mut BytesMut) -> Result<(bool, usize)> {
+/// read from client into the demux buffer
+async fn client_read(reader: &OwnedReadHalf, buf: &mut BytesMut) -> Result<usize> {
     loop {
         // wait until we can read from the stream
         match reader.readable().await {
             Ok(()) => {
                 match reader.try_read_buf(buf) {
                     Ok(0) => return Err(Error::new(ErrorKind::ConnectionReset,"client closed connection")),
-                    Ok(bytes_read) => return Ok(((buf[0] == 0x24), bytes_read)),
+                    Ok(bytes_read) => return Ok(bytes_read),
                     Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue, // try again
-                    Err(e) => return Err(e.into()),
+                    Err(e) => return Err(e),
                 }
             },
             Err(e) => {
                 error!("reader didn't become readable");
-                return Err(e.into())
+                return Err(e)
             },
         }
     }
 }
 
-/// read client messages until disconnected
-async fn client_reader(local_addr: String, remote_addr: String, reader: &OwnedReadHalf) -> Result<usize> {
-    let mut bytes_read: usize = 0;
-    let mut frag: Vec<u8> = Vec::new();
-        loop {
-        let mut buf = BytesMut::with_capacity(262168);
-        match client_read(&reader, &mut buf).await {
-            Ok((mut interleaved, mut length)) => {
-                bytes_read += length;
-                let mut data = &mut buf[..];
-
-                // if fragment left over then we want to add new buffer to it
-                // if not then we don't want to copy (fast path)
-                if frag.len() > 0 {
-                    debug!("fragment length is {}, new buffer length is {}", frag.len(), length);
-                    length += frag.len();
-                    interleaved = true;
-                    frag.append(&mut buf.to_vec());
-                    data = &mut frag[..];
+/// interleaved channel pairs in the Transport header of a SETUP request or response
+fn client_interleaved(message: &RtspMessage) -> Vec<(u8, u8)> {
+    let transport = match message.headers().transport() {
+        Some(transport) => transport,
+        None => return Vec::new(),
+    };
+
+    match RtspTransport::parse_header(transport) {
+        Ok(transports) => return transports.iter().filter(|transport| transport.is_tcp()).filter_map(|transport| transport.interleaved()).collect(),
+        Err(e) => {
+            warn!("unable to parse Transport header: {}", e);
+            return Vec::new()
+        },
+    }
+}
+
+/// map any interleaved channels in a SETUP request or response to DP tracks
+/// returns the RTP channels of the pairs newly mapped, and maps none of them if any pair can't be mapped
+async fn client_map_channels(flow: &DpFlow, message: &RtspMessage) -> Result<Vec<u8>> {
+    let mut mapped = Vec::new();
+    for (rtp_channel, rtcp_channel) in client_interleaved(message) {
+        match dp_channels(flow, rtp_channel, rtcp_channel).await {
+            Ok(true) => {
+                debug!("interleaved channels {}/{} mapped", rtp_channel, rtcp_channel);
+                mapped.push(rtp_channel);
+            },
+            Ok(false) => trace!("interleaved channels {}/{} kept", rtp_channel, rtcp_channel),
+            Err(e) => {
+                for channel in mapped {
+                    dp_remove_channels(flow, channel);
                 }
+                return Err(Error::new(e.kind(), format!("interleaved channels {}/{}: {}", rtp_channel, rtcp_channel, e)))
+            },
+        }
+    }
+    return Ok(mapped)
+}
+
+/// map the interleaved channels a client asks for in SETUP, noting them so they can be released if the SETUP fails
+/// false if they can't be mapped, in which case the client is told its transport is unsupported
+async fn client_setup_channels(stub: &Stub, flow: &DpFlow, request: &RtspRequest, message: &RtspMessage, setups: &PendingSetups, flow_key: &FlowKey) -> bool {
+    match client_map_channels(flow, message).await {
+        Ok(channels) => {
+            if let (Some(cseq), false) = (request.headers.cseq(), channels.is_empty()) {
+                lock(setups).entry(cseq).or_default().channels = channels;
+            }
+            return true
+        },
+        Err(e) => {
+            warn!("unable to map SETUP {}, rejecting it", e);
+            client_reject(stub, flow_key, request, 461, "Unsupported Transport").await;
+            return false
+        },
+    }
+}
+
+/// response to a request the stub answers itself, with the request's CSeq and Session
+fn client_reply(request: &RtspRequest, status: u16, reason: &str) -> RtspResponse {
+    let mut response = RtspResponse::new(status, reason);
+    for name in ["CSeq", "Session"] {
+        if let Some(value) = request.headers.get(name) {
+            response.headers.set(name, value);
+        }
+    }
+    return response
+}
+
+/// answer a request the stub won't pass on to the CP
+async fn client_reject(stub: &Stub, flow_key: &FlowKey, request: &RtspRequest, status: u16, reason: &str) {
+    match cp_reply(stub, flow_key, client_reply(request, status, reason).to_bytes()).await {
+        Ok(()) => trace!("{} queued for client", status),
+        Err(e) => warn!("unable to reject {}: {}", request.method, e),
+    }
+}
+
+/// turn a client SETUP for RTP over UDP into an interleaved SETUP towards the CP
+/// the stub relays the media between the client's UDP ports and the DP itself
+async fn client_udp_setup(flow: &DpFlow, request: &mut RtspMessage, remote_addr: &str, setups: &PendingSetups) -> Result<bool> {
+    let cseq = match request {
+        RtspMessage::Request(setup) if setup.method == RtspMethod::Setup => setup.headers.cseq(),
+        _ => return Ok(false),
+    };
 
-                if interleaved {
-                    trace!("Sending {} bytes to DP", length);
-                    match dp_demux(length, data).await {
-                        Ok((fragment, written, offset)) => {
-                            trace!("Sent {} bytes to DP", written);
-                            if fragment {
-                                debug!("unfinished buffer");
-                                frag = (offset).to_vec();
-                            } else {
-                                frag.clear();
+    let transports = match request.headers().transport() {
+        Some(transport) => {
+            match RtspTransport::parse_header(transport) {
+                Ok(transports) => transports,
+                Err(e) => return Err(e),
+            }
+        },
+        None => return Ok(false),
+    };
+
+    // use the client's first choice
+    let transport = match transports.into_iter().next() {
+        Some(transport) if !transport.is_tcp() && transport.param("multicast").is_none() => transport,
+        _ => return Ok(false),
+    };
+
+    let (client_rtp_port, client_rtcp_port) = match transport.client_port() {
+        Some(ports) => ports,
+        None => return Err(Error::new(ErrorKind::InvalidInput, "UDP transport without client_port")),
+    };
+
+    let cseq = match cseq {
+        Some(cseq) => cseq,
+        None => return Err(Error::new(ErrorKind::InvalidInput, "SETUP without CSeq")),
+    };
+
+    let client_ip = match SocketAddr::from_str(remote_addr) {
+        Ok(address) => address.ip(),
+        Err(e) => return Err(Error::new(ErrorKind::InvalidInput, e.to_string())),
+    };
+
+    match dp_channels_udp(flow, SocketAddr::new(client_ip, client_rtp_port), SocketAddr::new(client_ip, client_rtcp_port)).await {
+        Ok((rtp_channel, rtcp_channel, server_port)) => {
+            let mut interleaved = RtspTransport {
+                protocol: "RTP/AVP/TCP".to_string(),
+                params: transport.params.iter()
+                    .filter(|(name, _)| !["client_port", "server_port", "destination", "source", "port"].iter().any(|skip| name.eq_ignore_ascii_case(skip)))
+                    .cloned()
+                    .collect(),
+            };
+            interleaved.set_param("interleaved", Some(&format!("{}-{}", rtp_channel, rtcp_channel)));
+            request.headers_mut().set("Transport", &interleaved.to_string());
+
+            lock(setups).insert(cseq, PendingSetup { udp: Some(UdpSetup { transport, rtp_channel, server_port }), channels: Vec::new() });
+            return Ok(true)
+        },
+        Err(e) => return Err(e),
+    }
+}
+
+/// finish a SETUP once the CP answers it, releasing any channels mapped for the request that the server didn't keep
+/// and rewriting the response to a UDP SETUP back into the transport the client asked for
+fn client_setup_response(flow: &DpFlow, response: &mut RtspMessage, setups: &PendingSetups) -> bool {
+    let (cseq, status) = match response {
+        RtspMessage::Response(reply) => (reply.headers.cseq(), reply.status),
+        _ => return false,
+    };
+
+    let setup = match cseq {
+        Some(cseq) => lock(setups).remove(&cseq),
+        None => None,
+    };
+
+    let setup = match setup {
+        Some(setup) => setup,
+        None => return false,
+    };
+
+    let succeeded = (200..300).contains(&status);
+    let chosen: Vec<u8> = if succeeded {
+        client_interleaved(response).into_iter().map(|(rtp_channel, _)| rtp_channel).collect()
+    } else {
+        Vec::new()
+    };
+    for channel in setup.channels.into_iter().filter(|channel| !chosen.contains(channel)) {
+        debug!("releasing channel {} mapped for SETUP", channel);
+        dp_remove_channels(flow, channel);
+    }
+
+    let setup = match setup.udp {
+        Some(setup) => setup,
+        None => return false,
+    };
+
+    if !succeeded {
+        debug!("UDP SETUP failed with status {}", status);
+        dp_remove_channels(flow, setup.rtp_channel);
+        return false
+    }
+
+    let mut transport = setup.transport;
+    transport.set_param("server_port", Some(&format!("{}-{}", setup.server_port, setup.server_port + 1)));
+
+    // keep what the server chose for the stream
+    let chosen = response.headers().transport()
+        .and_then(|value| RtspTransport::parse_header(value).ok())
+        .and_then(|transports| transports.into_iter().next());
+    if let Some(chosen) = chosen {
+        for name in ["ssrc", "mode"] {
+            if let Some(value) = chosen.param(name) {
+                transport.set_param(name, Some(value));
+            }
+        }
+    }
+
+    response.headers_mut().set("Transport", &transport.to_string());
+    return true
+}
+
+/// span for one RTSP message, so every line logged while handling it carries its CSeq
+fn client_rtsp_span(message: Option<&RtspMessage>) -> Span {
+    let span = info_span!("rtsp", cseq = field::Empty, method = field::Empty);
+    if let Some(message) = message {
+        if let Some(cseq) = message.headers().cseq() {
+            span.record("cseq", cseq);
+        }
+        if let RtspMessage::Request(request) = message {
+            span.record("method", field::display(&request.method));
+        }
+    }
+    return span
+}
+
+/// inspect one RTSP message from the client and pass it on to the CP
+async fn client_rtsp(stub: &Stub, dp_flow: Option<&DpFlow>, setups: &PendingSetups, rtsp_session: &FlowSession, flow_key: &FlowKey, mut message: BytesMut, parsed: Result<RtspMessage>) -> Result<()> {
+    let mut session = None;
+    let parsed = match parsed {
+        Ok(mut parsed) => {
+            session = parsed.headers().session_id().map(|id| id.to_string());
+            debug!("RTSP {} from client", parsed);
+            if let RtspMessage::Request(request) = &parsed {
+                stub.metrics.rtsp_request(&request.method);
+                lock(rtsp_session).request(request);
+            }
+            if let Some(flow) = dp_flow {
+                match client_udp_setup(flow, &mut parsed, &flow_key.remote, setups).await {
+                    Ok(true) => {
+                        debug!("UDP SETUP rewritten as {}", parsed.headers().transport().unwrap_or_default());
+                        message = BytesMut::from(&parsed.to_bytes()[..]);
+                    },
+                    Ok(false) => {
+                        if let RtspMessage::Request(request @ RtspRequest { method: RtspMethod::Setup, .. }) = &parsed {
+                            // the stub has answered a SETUP it can't carry, so the CP never sees it
+                            if !client_setup_channels(stub, flow, request, &parsed, setups, flow_key).await {
+                                return Ok(())
                             }
-                        },
-                        Err(e) => error!("Error sending client data to DP: {}", e.to_string()),
+                        }
+                    },
+                    Err(e) => {
+                        // the CP would set up a transport the stub can't carry, so the client is told now
+                        warn!("unable to set up UDP transport, rejecting SETUP: {}", e);
+                        if let RtspMessage::Request(request) = &parsed {
+                            client_reject(stub, flow_key, request, 461, "Unsupported Transport").await;
+                        }
+                        return Ok(())
+                    },
+                }
+            } else if let RtspMessage::Request(request @ RtspRequest { method: RtspMethod::Setup, .. }) = &parsed {
+                // a flow accepted before the CP configured the DP has no sockets to carry media
+                warn!("no DP sockets for flow, rejecting SETUP");
+                client_reject(stub, flow_key, request, 503, "Service Unavailable").await;
+                return Ok(())
+            }
+            Some(parsed)
+        },
+        Err(e) => {
+            warn!("unable to parse client RTSP message: {}", e);
+            None
+        },
+    };
+
+    // this is control plane data from client, passed on byte for byte as bodies need not be UTF-8
+    debug!("Client request length {}, request is {}", message.len(), String::from_utf8_lossy(&message));
+
+    // Tell CP thread to send data to CP
+    // while the CP is down the stub answers requests itself and keeps the flow, which is replayed to the CP once it is back
+    match cp_data(stub, flow_key, message.to_vec(), session.as_deref()).await {
+        Ok(()) => {
+            trace!("written to CP");
+            return Ok(())
+        },
+        Err(ref e) if e.kind() == ErrorKind::NotConnected => {
+            warn!("CP unavailable, answering client with 503");
+            if let Some(RtspMessage::Request(request)) = &parsed {
+                client_reject(stub, flow_key, request, 503, "Service Unavailable").await;
+            }
+            return Ok(())
+        },
+        Err(e) => return Err(Error::new(ErrorKind::ConnectionAborted, e.to_string())),
+    }
+}
+
+/// dispatch each complete interleaved frame to the DP and each complete RTSP message to the CP
+/// any RTSP message from the client keeps its session alive, as do RTCP reports (see dp_send)
+async fn client_dispatch(stub: &Stub, dp_flow: Option<&DpFlow>, setups: &PendingSetups, pending: &PendingRequests, rtsp_session: &FlowSession, flow_key: &FlowKey, demux: &mut ClientDemux) -> Result<()> {
+    loop {
+        match demux.next_message() {
+            Ok(Some(ClientMessage::Interleaved { channel, data })) => {
+                trace!("Sending {} bytes to DP on channel {}", data.len(), channel);
+                match dp_flow {
+                    Some(flow) => {
+                        match dp_send(flow, &data, channel).await {
+                            Ok(written) => trace!("Sent {} bytes to DP", written),
+                            Err(ref e) if e.kind() == ErrorKind::PermissionDenied => trace!("dropped {} bytes on channel {}: {}", data.len(), channel, e),
+                            Err(e) => error!("Error sending client data to DP: {}", e),
+                        }
+                    },
+                    None => trace!("no DP sockets for flow, dropping {} bytes", data.len()),
+                }
+            },
+            Ok(Some(ClientMessage::Rtsp(message))) => {
+                lock(rtsp_session).keepalive().touch();
+                let parsed = RtspMessage::parse(&message);
+                let span = client_rtsp_span(parsed.as_ref().ok());
+                if let Ok(RtspMessage::Request(request)) = &parsed {
+                    if let Some(cseq) = request.headers.cseq() {
+                        lock(pending).insert(cseq, span.clone());
                     }
-                } else {
-                    // this is control plane data from client
-                    // from_utf8_lossy means we can handle the case where we have invalid UTF-8
-                    // but may only be because of incorrectly received data so swith back to from_utf8 once that's fixed?
-                    let request_string = String::from_utf8_lossy(&buf).to_string();
-                    debug!("Client request length {}, request is {}", request_string.len(), request_string);
-
-                    // Tell CP thread to send data to CP
-                    match cp_data(local_addr.clone(), remote_addr.clone(), request_string).await {
-                        Ok(()) => trace!("written to CP"),
-                        Err(e) => return Err(Error::new(ErrorKind::ConnectionAborted, e.to_string())),
+                }
+                match client_rtsp(stub, dp_flow, setups, rtsp_session, flow_key, message, parsed).instrument(span).await {
+                    Ok(()) => trace!("RTSP message passed on"),
+                    Err(e) => return Err(e),
+                }
+            },
+            Ok(None) => {
+                if demux.buffered() > 0 {
+                    debug!("{} bytes of unfinished client data buffered", demux.buffered());
+                    if demux.partial_frame() {
+                        stub.metrics.interleaved_fragments.inc();
                     }
                 }
+                return Ok(())
+            },
+            Err(e) => {
+                stub.metrics.demux_errors.inc();
+                return Err(e)
+            },
+        }
+    }
+}
+
+/// read client messages until disconnected
+async fn client_reader(stub: &Stub, dp_flow: Option<&DpFlow>, setups: &PendingSetups, pending: &PendingRequests, rtsp_session: &FlowSession, flow_key: &FlowKey, reader: &OwnedReadHalf) -> Result<usize> {
+    let mut bytes_read: usize = 0;
+    let mut demux = ClientDemux::new();
+    loop {
+        let read = tokio::select! {
+            read = client_read(reader, demux.read_buf()) => read,
+            _ = stub.shutdown_requested() => {
+                debug!("stub shutting down, closing client");
+                break
+            },
+        };
+
+        match read {
+            Ok(length) => {
+                bytes_read += length;
+
+                // a read may hold any mix of interleaved frames and RTSP messages, and may end part way through either
+                match client_dispatch(stub, dp_flow, setups, pending, rtsp_session, flow_key, &mut demux).await {
+                    Ok(()) => trace!("client data dispatched"),
+                    Err(e) => return Err(e),
+                }
             },
             Err(ref e) if e.kind() == ErrorKind::ConnectionReset => {
                 debug!("connection reset");
                 break
             },
-            Err(e) => return Err(e.into()),
+            Err(e) => return Err(e),
         }
     }
 
     return Ok(bytes_read)
 }
 
-/// reflect back to client
-async fn client_write(writer: &OwnedWriteHalf, response: Vec<u8>) -> Result<usize> {
-    trace!("writing {} bytes to client", response.len());
-    loop {
+/// write as much of the front frame as the socket takes without blocking
+fn client_write(writer: &OwnedWriteHalf, queue: &mut ClientQueue) -> Result<usize> {
+    let written = match queue.front() {
+        Some(frame) => {
+            trace!("writing {} bytes to client", frame.len());
+            match writer.try_write(frame) {
+                Ok(bytes) => bytes,
+                Err(e) => return Err(e),
+            }
+        },
+        None => return Ok(0),
+    };
+
+    queue.advance(written);
+    return Ok(written)
+}
+
+/// write everything queued, waiting for the client as long as it takes
+async fn client_flush(writer: &OwnedWriteHalf, queue: &mut ClientQueue) -> Result<usize> {
+    let mut written = 0;
+    while !queue.is_empty() {
         match writer.writable().await {
             Ok(()) => {
-                match writer.try_write(&response) {
-                    Ok(bytes) => {
-                        debug!("{} bytes written", bytes);
-                        return Ok(bytes)
-                    },
-                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue, // try again
-                    Err(e) => return Err(e.into()),
+                match client_write(writer, queue) {
+                    Ok(bytes) => written += bytes,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
                 }
             },
-            Err(e) => {
-                error!("writer didn't become writeable");
-                return Err(e.into())
-            },
+            Err(e) => return Err(e),
         }
     }
+    return Ok(written)
+}
+
+/// span for a message from the CP, under the client request it answers if there is one
+/// the span follows from the CP span, so a trace runs from the client request through the CP and back
+fn client_response_span(pending: &PendingRequests, message: Option<&RtspMessage>, cp_span: &Span) -> Span {
+    let request = match message {
+        Some(RtspMessage::Response(response)) => {
+            response.headers.cseq().and_then(|cseq| lock(pending).remove(&cseq).map(|span| (cseq, span)))
+        },
+        _ => None,
+    };
+
+    let span = match request {
+        Some((cseq, request_span)) => info_span!(parent: &request_span, "cp_response", cseq = cseq),
+        None => client_rtsp_span(message),
+    };
+    span.follows_from(cp_span);
+    return span
 }
 
-/// handle messages for client
-async fn client_writer(mut rx: mpsc::Receiver<Vec<u8>>, writer: OwnedWriteHalf) -> Result<usize> {
+/// move the flow's RTSP session on with a response from the CP
+/// media from the client goes to the DP only while playing or recording, and TEARDOWN releases the flow's tracks
+fn client_session_response(dp_flow: Option<&DpFlow>, rtsp_session: &FlowSession, response: &RtspResponse) {
+    let (state, media_allowed) = {
+        let mut rtsp_session = lock(rtsp_session);
+        (rtsp_session.response(response), rtsp_session.media_allowed())
+    };
+
+    if let (Some(state), Some(flow)) = (state, dp_flow) {
+        dp_allow_client_media(flow, media_allowed);
+        if state == SessionState::Init {
+            debug!("session torn down, releasing DP tracks");
+            dp_remove_tracks(flow);
+        }
+    }
+}
+
+/// pass CP responses on to the client, mapping the interleaved channels negotiated in SETUP
+async fn client_responses(dp_flow: Option<Arc<DpFlow>>, setups: PendingSetups, pending: PendingRequests, rtsp_session: FlowSession, mut cp_rx: mpsc::Receiver<(Vec<u8>, Span)>, tx: mpsc::Sender<(Vec<u8>, Span)>) -> Result<usize> {
+    let mut responses = 0;
+    let mut session: Option<String> = None;
+
+    while let Some((mut response, cp_span)) = cp_rx.recv().await {
+        let parsed = RtspMessage::parse(&response);
+        let span = client_response_span(&pending, parsed.as_ref().ok(), &cp_span);
+        async {
+            match parsed {
+                Ok(mut parsed) => {
+                    debug!("RTSP {} from CP", parsed);
+                    if let Some(flow) = &dp_flow {
+                        if client_setup_response(flow, &mut parsed, &setups) {
+                            debug!("UDP SETUP response rewritten as {}", parsed.headers().transport().unwrap_or_default());
+                            response = parsed.to_bytes();
+                        } else if let Err(e) = client_map_channels(flow, &parsed).await {
+                            warn!("unable to map channels from CP: {}", e);
+                        }
+                    }
+                    // the session records the transport the client sees, after any UDP rewrite
+                    if let RtspMessage::Response(reply) = &parsed {
+                        client_session_response(dp_flow.as_deref(), &rtsp_session, reply);
+                    }
+                },
+                Err(e) => warn!("unable to parse CP RTSP message: {}", e),
+            }
+        }.instrument(span.clone()).await;
+
+        // tag the flow with its RTSP session once the server has given one
+        {
+            let rtsp_session = lock(&rtsp_session);
+            if let Some(id) = rtsp_session.id() {
+                if session.as_deref() != Some(id) {
+                    Span::current().record("session", id);
+                    session = Some(id.to_string());
+                }
+            }
+        }
+
+        // the writer closes the span once the response is on its way to the client
+        match tx.send((response, span)).await {
+            Ok(()) => responses += 1,
+            Err(e) => return Err(Error::new(ErrorKind::BrokenPipe, e.to_string())),
+        }
+    }
+
+    return Ok(responses)
+}
+
+/// queue a frame for the client, false if the client has fallen too far behind to keep
+fn client_enqueue(metrics: &Metrics, queue: &mut ClientQueue, message: Bytes, kind: FrameKind, span: Span) -> bool {
+    trace!("received {} bytes for client", message.len());
+    match queue.push(message, kind, span) {
+        Queued::Queued => return true,
+        Queued::Dropped => {
+            trace!("client behind by {} bytes, {:?} frame dropped", queue.queued(), kind);
+            let media = if kind == FrameKind::Rtcp { MEDIA_RTCP } else { MEDIA_RTP };
+            metrics.client_dropped_frames.with_label_values(&[media]).inc();
+            return true
+        },
+        Queued::Overflow => {
+            warn!("client behind by {} bytes, disconnecting", queue.queued());
+            metrics.slow_client_disconnects.inc();
+            return false
+        },
+    }
+}
+
+/// handle messages for client until the client goes or the CP closes the flow
+/// messages are queued and written whole as the client takes them, see ClientQueue for what happens to a slow client
+/// CP messages come with the span of the request they answer, which ends once they are written
+/// on a CP close any final message is written before the connection is shut down
+/// returns the bytes written and whether the CP closed the flow
+async fn client_writer(metrics: Metrics, mut rx: mpsc::Receiver<(FrameKind, Bytes)>, mut cp_rx: mpsc::Receiver<(Vec<u8>, Span)>, mut close_rx: oneshot::Receiver<Option<Vec<u8>>>, mut writer: OwnedWriteHalf) -> Result<(usize, bool)> {
     let mut written_back = 0;
+    let mut queue = ClientQueue::new();
+    let mut media_open = true;
+    let mut cp_open = true;
 
-    while let Some(message) = rx.recv().await {
-        trace!("received {} bytes for client", message.len());
-        match client_write(&writer, message).await {
-            Ok(bytes) => written_back += bytes,
-            Err(ref e) => {
-                if e.kind() == ErrorKind::ConnectionReset {
-                    warn!("Connecton reset by client");
-                    break;
-                } else {
-                    error!("Error writing to client: {}", e);
+    while media_open || cp_open || !queue.is_empty() {
+        tokio::select! {
+            close = &mut close_rx => {
+                // finish any frame already started so the final message isn't written into the middle of it
+                let dropped = queue.drop_media();
+                trace!("{} media frames dropped on close", dropped);
+                if let Ok(Some(message)) = close {
+                    let len = message.len();
+                    match queue.push(Bytes::from(message), FrameKind::Control, Span::none()) {
+                        Queued::Overflow => warn!("client behind by {} bytes, closing without final {} byte message", queue.queued(), len),
+                        _ => trace!("final {} byte message queued", len),
+                    }
+                }
+                match timeout(CLIENT_FLUSH_TIMEOUT, client_flush(&writer, &mut queue)).await {
+                    Ok(Ok(bytes)) => written_back += bytes,
+                    Ok(Err(e)) => warn!("unable to send final message to client: {}", e),
+                    Err(_) => warn!("client still behind {} bytes on close", queue.queued()),
+                }
+                match writer.shutdown().await {
+                    Ok(()) => debug!("client connection closed by CP"),
+                    Err(e) => debug!("error closing client connection: {}", e),
+                }
+                return Ok((written_back, true))
+            },
+            message = cp_rx.recv(), if cp_open => {
+                match message {
+                    Some((message, span)) => {
+                        // the SDP says which RTP payload types are video whose keyframes can be found
+                        if let Ok(parsed) = RtspMessage::parse(&message) {
+                            for (payload_type, codec) in sdp_codecs(parsed.body()) {
+                                debug!("RTP payload type {} is {:?}", payload_type, codec);
+                                queue.set_codec(payload_type, codec);
+                            }
+                        }
+                        let span = info_span!(parent: &span, "client_write", bytes = message.len());
+                        if !client_enqueue(&metrics, &mut queue, Bytes::from(message), FrameKind::Control, span) {
+                            break
+                        }
+                    },
+                    None => cp_open = false,
+                }
+            },
+            message = rx.recv(), if media_open => {
+                match message {
+                    Some((kind, message)) => {
+                        if !client_enqueue(&metrics, &mut queue, message, kind, Span::none()) {
+                            break
+                        }
+                    },
+                    None => media_open = false,
+                }
+            },
+            writable = writer.writable(), if !queue.is_empty() => {
+                match writable {
+                    Ok(()) => {
+                        match client_write(&writer, &mut queue) {
+                            Ok(bytes) => written_back += bytes,
+                            Err(ref e) if e.kind() == ErrorKind::WouldBlock => trace!("client not ready after all"),
+                            Err(ref e) if e.kind() == ErrorKind::ConnectionReset || e.kind() == ErrorKind::BrokenPipe => {
+                                warn!("Connecton reset by client");
+                                break
+                            },
+                            Err(e) => {
+                                error!("Error writing to client: {}", e);
+                                return Err(e)
+                            },
+                        }
+                    },
+                    Err(e) => {
+                        error!("writer didn't become writeable");
+                        return Err(e)
+                    },
                 }
             },
         }
-        trace!("about to loop again");
     }
 
-    return Ok(written_back);
+    return Ok((written_back, false));
+}
+
+/// completes once the client has gone quiet for longer than its RTSP session timeout
+/// flows without a session (before SETUP or after TEARDOWN) expire after the default timeout
+async fn client_session_expiry(rtsp_session: &FlowSession) -> Duration {
+    let keepalive = lock(rtsp_session).keepalive().clone();
+
+    loop {
+        let session_timeout = lock(rtsp_session).timeout();
+
+        let idle = keepalive.idle();
+        let wait = match (session_timeout + CLIENT_SESSION_GRACE).checked_sub(idle) {
+            Some(remaining) if !remaining.is_zero() => remaining.min(CLIENT_SESSION_CHECK_INTERVAL),
+            _ => return idle,
+        };
+
+        trace!("checking RTSP session timeout in {:?}", wait);
+        sleep(wait).await;
+    }
 }
 
 /// handle client connection
-async fn client_handler(local_addr: String, remote_addr: String, client_stream: TcpStream) -> Result<()> {
+async fn client_handler(stub: Arc<Stub>, local_addr: String, remote_addr: String, client_stream: TcpStream) -> Result<()> {
+
+    // shutdown waits for this to drop, by which time the flow has been deleted from the CP
+    let flow_guard = stub.flow_guard();
+
+    // every line logged for the flow, including from its tasks, carries its id and addresses
+    let span = info_span!("flow", id = flow_guard.id(), local = %local_addr, remote = %remote_addr, session = field::Empty);
+
+    return client_flow(stub, FlowKey::new(local_addr, remote_addr), client_stream).instrument(span).await
+}
 
-    trace!("client handler for {} {}", local_addr, remote_addr);
+/// run a client flow until the client goes or the CP closes it
+async fn client_flow(stub: Arc<Stub>, flow_key: FlowKey, client_stream: TcpStream) -> Result<()> {
+
+    trace!("client handler started");
 
     // Need socket to flush messages immediately 
     match client_stream.set_nodelay(true) {
@@ -174,63 +739,131 @@ async fn client_handler(local_addr: String, remote_addr: String, client_stream:
             // split socket into sender/receiver so can hand sender to separate thread
             let (reader, writer) = client_stream.into_split();
 
-            // Create channel to receive messages for client
-            let (tx, rx) = mpsc::channel::<Vec<u8>>(CLIENT_CHANNEL_SIZE);
+            // Create channel to receive media for client
+            // it is drained straight into the writer's queue, so it only has to cover a burst of datagrams
+            let (tx, rx) = mpsc::channel::<(FrameKind, Bytes)>(CLIENT_MEDIA_CHANNEL_SIZE);
+
+            // and a channel for messages from the CP, which are inspected on the way through
+            let (cp_tx, cp_rx) = mpsc::channel::<(Vec<u8>, Span)>(CLIENT_CHANNEL_SIZE);
+
+            // which go on to the writer with the span of the request they answer
+            let (response_tx, response_rx) = mpsc::channel::<(Vec<u8>, Span)>(CLIENT_CHANNEL_SIZE);
+
+            // the CP closes the flow through the flow table
+            let (close_tx, close_rx) = oneshot::channel::<Option<Vec<u8>>>();
+
+            // RTSP messages and RTCP reports from the client keep the flow alive
+            let keepalive = Arc::new(Keepalive::new());
+
+            // the flow gets its own RTP/RTCP sockets so it only receives its own media
+            let dp_flow = match dp_flow(&stub, tx, keepalive.clone()).await {
+                Ok(flow) => Some(Arc::new(flow)),
+                Err(e) => {
+                    warn!("no DP sockets for client: {}", e);
+                    None
+                },
+            };
+            let rtp_port = dp_flow.as_ref().map(|flow| flow.rtp_port());
 
             // add the client flow to the CP
             // in inbound case this will be unsolicited
             // in outbound case the CP has already sent us a request to add the flow
-            match cp_add(tx.clone(), local_addr.clone(), remote_addr.clone()).await {
+            match cp_add(&stub, FlowHandle { tx: cp_tx, close: close_tx, rtp_port }, &flow_key).await {
                 Ok(()) => {
                     let mut handles = vec![];
-                    let rtp_tx = tx.clone();
-                    let rtcp_tx = tx.clone();
 
                     // Spawn thread to receive messages and send to client
-                    handles.push(tokio::spawn(async move {
+                    // it finishes early if the CP closes the flow
+                    let writer_metrics = stub.metrics.clone();
+                    let mut writer_handle = tokio::spawn(async move {
                         trace!("spawning thread to send messages to client");
-                        match client_writer(rx, writer).await {
-                            Ok(written) => {
+                        match client_writer(writer_metrics, rx, response_rx, close_rx, writer).await {
+                            Ok((written, closed_by_cp)) => {
                                 debug!("Disconnected: wrote total of {} bytes back to client", written);
+                                return closed_by_cp
+                            },
+                            Err(e) => {
+                                error!("Error: {}", e);
+                                return false
                             },
-                            Err(e) => error!("Error: {}", e),
-                        }
-                    }));
-
-                    // need to listen for RTP/RTCP messages
-                    handles.push(tokio::spawn(async move {
-                        trace!("spawning thread for RTP receive");
-                        match dp_rtp_recv(rtp_tx).await {
-                            Ok(written) => info!("{} RTP bytes read", written),
-                            Err(e) => debug!("RTP read error {}", e.to_string()),
                         }
-                    }));
+                    }.in_current_span());
 
+                    // RTP/RTCP from the DP is received by the flow's own tasks
+                    // CP responses may carry the interleaved channels for each track
+                    // and the client may have asked for RTP over UDP, which the stub terminates
+                    let setups = PendingSetups::default();
+                    let pending = PendingRequests::default();
+                    let rtsp_session = FlowSession::new(Mutex::new(RtspSession::new(stub.metrics.clone(), keepalive.clone())));
+                    // the session is listed by the stub until the flow ends
+                    let _session_guard = stub.session_guard(&flow_key, rtsp_session.clone());
+                    let response_flow = dp_flow.clone();
+                    let response_setups = setups.clone();
+                    let response_pending = pending.clone();
+                    let response_session = rtsp_session.clone();
                     handles.push(tokio::spawn(async move {
-                        trace!("spawning thread for RTCP receive");
-                        match dp_rtcp_recv(rtcp_tx).await {
-                            Ok(written) => info!("{} RTCP bytes read", written),
-                            Err(e) => debug!("RTCP read error {}", e.to_string()),
+                        trace!("spawning thread for CP responses");
+                        match client_responses(response_flow, response_setups, response_pending, response_session, cp_rx, response_tx).await {
+                            Ok(responses) => debug!("{} CP responses sent to client", responses),
+                            Err(e) => debug!("CP response error {}", e),
                         }
-                    }));
+                    }.in_current_span()));
 
-                    // now read messages from client until it finishes
-                    match client_reader(local_addr.clone(), remote_addr.clone(), &reader).await {
-                        Ok(bytes_read) => debug!("read {} bytes from client", bytes_read),
-                        Err(e) => return Err(Error::new(ErrorKind::NotConnected, e.to_string())),
-                    }
+                    // read messages from client until it finishes, until the CP closes the flow
+                    // or until the client's RTSP session times out
+                    // a read error still tears the flow down below before it is returned
+                    let mut read_error = None;
+                    let closed_by_cp = tokio::select! {
+                        read = client_reader(&stub, dp_flow.as_deref(), &setups, &pending, &rtsp_session, &flow_key, &reader) => {
+                            match read {
+                                Ok(bytes_read) => debug!("read {} bytes from client", bytes_read),
+                                Err(e) => {
+                                    debug!("closing flow after client error: {}", e);
+                                    read_error = Some(Error::new(ErrorKind::NotConnected, e.to_string()));
+                                },
+                            }
+                            false
+                        },
+                        written = &mut writer_handle => {
+                            match written {
+                                Ok(true) => {
+                                    debug!("flow closed by CP");
+                                    true
+                                },
+                                _ => {
+                                    debug!("unable to write to client");
+                                    false
+                                },
+                            }
+                        },
+                        idle = client_session_expiry(&rtsp_session) => {
+                            warn!("client not heard from for {:?}, RTSP session timed out", idle);
+                            stub.metrics.session_timeouts.inc();
+                            false
+                        },
+                    };
 
                     trace!("waiting for threads to finish");
 
-                    // now kill the threads
+                    // now kill the threads, the flow's DP tasks go when the flow is dropped
+                    writer_handle.abort();
                     for handle in &handles {
                         handle.abort();
                     }
 
                     trace!("threads all finished");
-                
+
+                    // the CP has already removed the flow from the hashmap
+                    if closed_by_cp {
+                        return Ok(())
+                    }
+
                     // Tell CP thread to delete client from CP and from hashmap
-                    match cp_delete(local_addr.clone(), remote_addr.clone()).await {
+                    let deleted = cp_delete(&stub, &flow_key).await;
+                    if let Some(e) = read_error {
+                        return Err(e)
+                    }
+                    match deleted {
                         Ok(()) => return Ok(()),
                         Err(e) => return Err(Error::new(ErrorKind::NotConnected, e.to_string())),
                     }
@@ -240,13 +873,13 @@ async fn client_handler(local_addr: String, remote_addr: String, client_stream:
         },
         Err(e) => {
             error!("unable to set nodelay");
-            return Err(e.into())
+            return Err(e)
         },
     }
 }
 
 /// manage outbound client connection from beginning to end
-pub async fn client_outbound(remote_addr: String) -> Result<()> { 
+pub async fn client_outbound(stub: Arc<Stub>, remote_addr: String) -> Result<()> { 
     trace!("client_outbound for {}", remote_addr);
     match TcpStream::connect(remote_addr.clone()).await {
         Ok(client_stream) => {
@@ -255,7 +888,7 @@ pub async fn client_outbound(remote_addr: String) -> Result<()> {
                     let local_addr = address.to_string();
                     trace!("outbound connected from {}", local_addr);
                     tokio::spawn(async move {
-                        match client_handler(local_addr, remote_addr, client_stream).await {
+                        match client_handler(stub, local_addr, remote_addr, client_stream).await {
                             Ok(()) => debug!("Outbound client disconnected"),
                             Err(e) => error!("Outbound client error: {}", e),
                         }
@@ -266,28 +899,25 @@ pub async fn client_outbound(remote_addr: String) -> Result<()> {
                 Err(e) => return Err(Error::new(ErrorKind::AddrNotAvailable, e.to_string())),
             }
         },
-        Err(e) => return Err(e.into()),
+        Err(e) => return Err(e),
     }
 }
 
 /// creat inbound client connection
-async fn client_inbound(client_stream: TcpStream) -> Result<()> {
-    let local_addr: String;
-    let remote_addr: String;
-    
-    match client_stream.local_addr() {
-        Ok(address) => local_addr = address.to_string(),
-        Err(e) => return Err(e.into()),
-    }
+async fn client_inbound(stub: Arc<Stub>, client_stream: TcpStream) -> Result<()> {
+    let local_addr = match client_stream.local_addr() {
+        Ok(address) => address.to_string(),
+        Err(e) => return Err(e),
+    };
 
-    match client_stream.peer_addr() {
-        Ok(address) => remote_addr = address.to_string(),
-        Err(e) => return Err(e.into()),
-    }
+    let remote_addr = match client_stream.peer_addr() {
+        Ok(address) => address.to_string(),
+        Err(e) => return Err(e),
+    };
 
     // handler will run as its own thread (per client)
     tokio::spawn(async move {
-        match client_handler(local_addr, remote_addr, client_stream).await {
+        match client_handler(stub, local_addr, remote_addr, client_stream).await {
             Ok(()) => debug!("Inbound client disconnected"),
             Err(e) => error!("Inbound client error: {}", e),
         }
@@ -296,27 +926,417 @@ async fn client_inbound(client_stream: TcpStream) -> Result<()> {
     return Ok(())
 }
 
+/// accept clients until the stub shuts down
+async fn client_accept(stub: &Arc<Stub>, listener: TcpListener) -> Result<()> {
+    loop {
+
+        // will get socket handle plus IP/port for client
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = stub.shutdown_requested() => {
+                debug!("stub shutting down, no longer accepting clients");
+                return Ok(())
+            },
+        };
+
+        match accepted {
+            Ok((stream, client)) => {
+                debug!("connected, client is {}", client);
+                stub.metrics.accepted_connections.inc();
+
+                match client_inbound(stub.clone(), stream).await {
+                    Ok(()) => debug!("Inbound client spawned"),
+                    Err(e) => error!("Unable to spawn inbound client: {}", e),
+                }
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 /// Client listener
-pub async fn client_listener(socket: String) -> Result<()> {
+pub async fn client_listener(stub: Arc<Stub>, socket: String) -> Result<()> {
     match TcpListener::bind(socket).await {
         Ok(listener) => {
             debug!("Listening for connections");
-            loop {
-
-                // will get socket handle plus IP/port for client
-                match listener.accept().await {
-                    Ok((stream, client)) => {
-                        debug!("connected, client is {}", client.to_string());
-
-                        match client_inbound(stream).await {
-                            Ok(()) => debug!("Inbound client spawned"),
-                            Err(e) => error!("Unable to spawn inbound client: {}", e),
-                        }
-                    },
-                    Err(e) => return Err(e),
-                }
-            }
+            stub.set_listening(true);
+            let result = client_accept(&stub, listener).await;
+            stub.set_listening(false);
+            return result
         },
         Err(e) => return Err(e),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dp::{dp_init, DpProxy};
+
+    use opentelemetry::sdk::export::trace::SpanData;
+    use opentelemetry::sdk::trace::{Span as OtelSpan, SpanProcessor, TracerProvider};
+    use opentelemetry::trace::{SpanContext, TraceResult, TracerProvider as _};
+    use opentelemetry::{Context, Key};
+
+    use tokio::io::AsyncReadExt;
+
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Registry;
+
+    /// keeps the spans exported as they end
+    #[derive(Debug, Clone, Default)]
+    struct ExportedSpans(Arc<Mutex<Vec<SpanData>>>);
+
+    impl ExportedSpans {
+        /// the one span exported with the name and CSeq
+        fn span(&self, name: &str, cseq: Option<&str>) -> SpanData {
+            let spans: Vec<SpanData> = lock(&self.0).iter()
+                .filter(|span| span.name == name && span.attributes.get(&Key::new("cseq")).map(|value| value.as_str()).as_deref() == cseq)
+                .cloned()
+                .collect();
+            assert_eq!(spans.len(), 1, "{} spans with CSeq {:?} exported", name, cseq);
+            return spans[0].clone()
+        }
+    }
+
+    impl SpanProcessor for ExportedSpans {
+        fn on_start(&self, _span: &mut OtelSpan, _cx: &Context) {}
+
+        fn on_end(&self, span: SpanData) {
+            lock(&self.0).push(span);
+        }
+
+        fn force_flush(&self) -> TraceResult<()> {
+            Ok(())
+        }
+
+        fn shutdown(&mut self) -> TraceResult<()> {
+            Ok(())
+        }
+    }
+
+    fn rtsp(data: &[u8]) -> RtspMessage {
+        match RtspMessage::parse(data) {
+            Ok(message) => return message,
+            Err(e) => panic!("unable to parse {:?}: {}", String::from_utf8_lossy(data), e),
+        }
+    }
+
+    /// a stub configured with a DP proxy and a DP flow on it
+    async fn dp_test_flow() -> (Arc<Stub>, DpFlow) {
+        let port = std::net::UdpSocket::bind(("127.0.0.1", 0)).and_then(|socket| socket.local_addr()).map(|address| address.port()).expect("no free port");
+        let stub = Stub::new(port & !1);
+        dp_init(&stub, DpProxy::from_rtp(SocketAddr::from(([127, 0, 0, 1], 9)))).await.expect("DP not configured");
+        let (tx, _) = mpsc::channel(1);
+        let flow = dp_flow(&stub, tx, Arc::new(Keepalive::new())).await.expect("no DP flow");
+        return (stub, flow)
+    }
+
+    /// whether the flow has a track for the channel
+    async fn mapped(flow: &DpFlow, channel: u8) -> bool {
+        match dp_send(flow, b"", channel).await {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return false,
+            _ => return true,
+        }
+    }
+
+    #[test]
+    fn response_span_is_a_child_of_its_request_linked_to_the_cp() {
+        let exported = ExportedSpans::default();
+        let provider = TracerProvider::builder().with_span_processor(exported.clone()).build();
+        let subscriber = Registry::default().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let pending = PendingRequests::default();
+            let request_span = info_span!("rtsp", cseq = 3);
+            lock(&pending).insert(3, request_span.clone());
+            let cp_span = info_span!("cp_data");
+
+            let matched = client_response_span(&pending, Some(&rtsp(b"RTSP/1.0 200 OK\r\nCSeq: 3\r\n\r\n")), &cp_span);
+            assert!(lock(&pending).is_empty());
+
+            // a response to no pending request gets a span of its own, still linked to the CP
+            let unmatched = client_response_span(&pending, Some(&rtsp(b"RTSP/1.0 200 OK\r\nCSeq: 4\r\n\r\n")), &cp_span);
+
+            drop(matched);
+            drop(unmatched);
+            drop(request_span);
+            drop(cp_span);
+        });
+
+        let request = exported.span("rtsp", Some("3"));
+        let cp = exported.span("cp_data", None);
+        let links = |span: &SpanData| span.links.iter().map(|link| link.span_context().clone()).collect::<Vec<SpanContext>>();
+
+        let matched = exported.span("cp_response", Some("3"));
+        assert_eq!(matched.parent_span_id, request.span_context.span_id());
+        assert_eq!(matched.span_context.trace_id(), request.span_context.trace_id());
+        assert_eq!(links(&matched), vec![cp.span_context.clone()]);
+
+        let unmatched = exported.span("rtsp", Some("4"));
+        assert_ne!(unmatched.span_context.trace_id(), request.span_context.trace_id());
+        assert_eq!(links(&unmatched), vec![cp.span_context.clone()]);
+    }
+
+    const UDP_SETUP: &[u8] = b"SETUP rtsp://camera/stream/trackID=0 RTSP/1.0\r\nCSeq: 3\r\nTransport: RTP/AVP;unicast;client_port=40000-40001\r\n\r\n";
+
+    #[tokio::test]
+    async fn udp_setup_is_rewritten_both_ways() {
+        let (_stub, flow) = dp_test_flow().await;
+        let setups = PendingSetups::default();
+
+        // towards the CP the stub carries the track on the first free interleaved channels
+        let mut request = rtsp(UDP_SETUP);
+        assert!(client_udp_setup(&flow, &mut request, "127.0.0.1:50000", &setups).await.unwrap());
+        assert_eq!(request.headers().transport(), Some("RTP/AVP/TCP;unicast;interleaved=2-3"));
+        assert!(mapped(&flow, 2).await && mapped(&flow, 3).await);
+
+        // and back to the client as the UDP transport it asked for, with the server's choices
+        let mut response = rtsp(b"RTSP/1.0 200 OK\r\nCSeq: 3\r\nSession: 1234\r\nTransport: RTP/AVP/TCP;unicast;interleaved=2-3;ssrc=1234ABCD;mode=play\r\n\r\n");
+        assert!(client_setup_response(&flow, &mut response, &setups));
+        let transports = RtspTransport::parse_header(response.headers().transport().unwrap()).unwrap();
+        assert_eq!(transports.len(), 1);
+        let transport = &transports[0];
+        assert_eq!(transport.protocol, "RTP/AVP");
+        assert_eq!(transport.client_port(), Some((40000, 40001)));
+        let (server_rtp, server_rtcp) = transport.server_port().expect("no server_port");
+        assert!(server_rtp % 2 == 0 && server_rtcp == server_rtp + 1);
+        assert_eq!(transport.param("ssrc"), Some("1234ABCD"));
+        assert_eq!(transport.param("mode"), Some("play"));
+        assert_eq!(transport.interleaved(), None);
+        assert!(lock(&setups).is_empty());
+        assert!(mapped(&flow, 2).await);
+    }
+
+    #[tokio::test]
+    async fn udp_setup_needs_ports_and_cseq() {
+        let (_stub, flow) = dp_test_flow().await;
+        let setups = PendingSetups::default();
+
+        let mut request = rtsp(b"SETUP rtsp://camera/stream RTSP/1.0\r\nCSeq: 3\r\nTransport: RTP/AVP;unicast\r\n\r\n");
+        assert_eq!(client_udp_setup(&flow, &mut request, "127.0.0.1:50000", &setups).await.unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        let mut request = rtsp(b"SETUP rtsp://camera/stream RTSP/1.0\r\nTransport: RTP/AVP;unicast;client_port=40000-40001\r\n\r\n");
+        assert_eq!(client_udp_setup(&flow, &mut request, "127.0.0.1:50000", &setups).await.unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        // interleaved SETUPs and other requests are left alone
+        let mut request = rtsp(b"SETUP rtsp://camera/stream RTSP/1.0\r\nCSeq: 3\r\nTransport: RTP/AVP/TCP;unicast;interleaved=2-3\r\n\r\n");
+        assert!(!client_udp_setup(&flow, &mut request, "127.0.0.1:50000", &setups).await.unwrap());
+        let mut request = rtsp(b"PLAY rtsp://camera/stream RTSP/1.0\r\nCSeq: 4\r\n\r\n");
+        assert!(!client_udp_setup(&flow, &mut request, "127.0.0.1:50000", &setups).await.unwrap());
+        assert!(lock(&setups).is_empty());
+        assert!(!mapped(&flow, 2).await);
+    }
+
+    #[tokio::test]
+    async fn failed_udp_setup_releases_its_track() {
+        let (_stub, flow) = dp_test_flow().await;
+        let setups = PendingSetups::default();
+
+        let mut request = rtsp(UDP_SETUP);
+        assert!(client_udp_setup(&flow, &mut request, "127.0.0.1:50000", &setups).await.unwrap());
+        let mut response = rtsp(b"RTSP/1.0 461 Unsupported Transport\r\nCSeq: 3\r\n\r\n");
+        assert!(!client_setup_response(&flow, &mut response, &setups));
+        assert_eq!(response.headers().transport(), None);
+        assert!(!mapped(&flow, 2).await && !mapped(&flow, 3).await);
+        assert!(lock(&setups).is_empty());
+    }
+
+    /// map the channels of an interleaved SETUP for channels 4 and 5
+    async fn setup_channels(stub: &Stub, flow: &DpFlow, setups: &PendingSetups, flow_key: &FlowKey, cseq: u32) -> bool {
+        let message = rtsp(format!("SETUP rtsp://camera/stream RTSP/1.0\r\nCSeq: {}\r\nTransport: RTP/AVP/TCP;unicast;interleaved=4-5\r\n\r\n", cseq).as_bytes());
+        match &message {
+            RtspMessage::Request(request) => return client_setup_channels(stub, flow, request, &message, setups, flow_key).await,
+            RtspMessage::Response(_) => panic!("not a request"),
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_interleaved_setup_releases_its_channels() {
+        let (stub, flow) = dp_test_flow().await;
+        let setups = PendingSetups::default();
+        let flow_key = FlowKey::new("127.0.0.1:554".to_string(), "127.0.0.1:50000".to_string());
+
+        // the server refuses the SETUP
+        assert!(setup_channels(&stub, &flow, &setups, &flow_key, 3).await);
+        assert!(mapped(&flow, 4).await && mapped(&flow, 5).await);
+        let mut response = rtsp(b"RTSP/1.0 454 Session Not Found\r\nCSeq: 3\r\n\r\n");
+        assert!(!client_setup_response(&flow, &mut response, &setups));
+        assert!(!mapped(&flow, 4).await && !mapped(&flow, 5).await);
+        tokio::task::yield_now().await;
+
+        // the server picks other channels
+        assert!(setup_channels(&stub, &flow, &setups, &flow_key, 4).await);
+        let mut response = rtsp(b"RTSP/1.0 200 OK\r\nCSeq: 4\r\nSession: 1234\r\nTransport: RTP/AVP/TCP;unicast;interleaved=6-7\r\n\r\n");
+        assert!(!client_setup_response(&flow, &mut response, &setups));
+        assert!(!mapped(&flow, 4).await);
+        tokio::task::yield_now().await;
+
+        // the server keeps the client's channels
+        assert!(setup_channels(&stub, &flow, &setups, &flow_key, 5).await);
+        let mut response = rtsp(b"RTSP/1.0 200 OK\r\nCSeq: 5\r\nSession: 1234\r\nTransport: RTP/AVP/TCP;unicast;interleaved=4-5\r\n\r\n");
+        assert!(!client_setup_response(&flow, &mut response, &setups));
+        assert!(mapped(&flow, 4).await && mapped(&flow, 5).await);
+        assert!(lock(&setups).is_empty());
+    }
+
+    #[test]
+    fn reply_echoes_cseq_and_session() {
+        let request = match rtsp(b"SETUP rtsp://camera/stream RTSP/1.0\r\nCSeq: 7\r\nSession: 1234\r\nUser-Agent: test\r\n\r\n") {
+            RtspMessage::Request(request) => request,
+            RtspMessage::Response(_) => panic!("not a request"),
+        };
+        assert_eq!(client_reply(&request, 461, "Unsupported Transport").to_bytes(), b"RTSP/1.0 461 Unsupported Transport\r\nCSeq: 7\r\nSession: 1234\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn setup_channels_map_to_tracks() {
+        let (_stub, flow) = dp_test_flow().await;
+
+        let setup = rtsp(b"SETUP rtsp://camera/stream RTSP/1.0\r\nCSeq: 3\r\nTransport: RTP/AVP/TCP;unicast;interleaved=2-3,RTP/AVP/TCP;unicast;interleaved=6-7\r\n\r\n");
+        assert_eq!(client_map_channels(&flow, &setup).await.unwrap(), vec![2, 6]);
+        assert!(mapped(&flow, 3).await && mapped(&flow, 7).await);
+
+        // channels already mapped the same way are kept rather than mapped again
+        assert_eq!(client_map_channels(&flow, &setup).await.unwrap(), Vec::<u8>::new());
+        let setup = rtsp(b"SETUP rtsp://camera/stream RTSP/1.0\r\nCSeq: 4\r\nTransport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n\r\n");
+        assert_eq!(client_map_channels(&flow, &setup).await.unwrap(), Vec::<u8>::new());
+    }
+
+    #[tokio::test]
+    async fn setup_channels_map_all_or_none() {
+        let (_stub, flow) = dp_test_flow().await;
+
+        // a pair past the flow's block undoes the pairs mapped before it
+        let setup = rtsp(b"SETUP rtsp://camera/stream RTSP/1.0\r\nCSeq: 3\r\nTransport: RTP/AVP/TCP;interleaved=2-3,RTP/AVP/TCP;interleaved=7-8\r\n\r\n");
+        assert_eq!(client_map_channels(&flow, &setup).await.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert!(!mapped(&flow, 2).await && !mapped(&flow, 7).await);
+        tokio::task::yield_now().await;
+
+        // as does one overlapping a pair already mapped
+        let setup = rtsp(b"SETUP rtsp://camera/stream RTSP/1.0\r\nCSeq: 4\r\nTransport: RTP/AVP/TCP;interleaved=2-3,RTP/AVP/TCP;interleaved=1-2\r\n\r\n");
+        assert_eq!(client_map_channels(&flow, &setup).await.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert!(!mapped(&flow, 2).await && !mapped(&flow, 3).await);
+    }
+
+    /// a flow's session before SETUP
+    fn flow_session() -> FlowSession {
+        return Arc::new(Mutex::new(RtspSession::new(Metrics::new(), Arc::new(Keepalive::new()))))
+    }
+
+    /// a SETUP and the server's answer to it
+    fn setup(session: &FlowSession, response: &[u8]) {
+        match (rtsp(b"SETUP rtsp://camera/stream RTSP/1.0\r\nCSeq: 1\r\n\r\n"), rtsp(response)) {
+            (RtspMessage::Request(request), RtspMessage::Response(response)) => {
+                let mut session = lock(session);
+                session.request(&request);
+                session.response(&response);
+            },
+            other => panic!("not a SETUP and its response: {:?}", other),
+        }
+    }
+
+    /// how long after the start a flow's session expires, and how idle the client was by then
+    async fn expires(session: &FlowSession) -> (Duration, Duration) {
+        let start = tokio::time::Instant::now();
+        let idle = client_session_expiry(session).await;
+        return (start.elapsed(), idle)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flow_without_session_expires_after_default_timeout() {
+        // 60 seconds by default, plus the grace
+        let session = flow_session();
+        assert_eq!(expires(&session).await, (Duration::from_secs(65), Duration::from_secs(65)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_pushes_expiry_back() {
+        let session = flow_session();
+        setup(&session, b"RTSP/1.0 200 OK\r\nCSeq: 1\r\nSession: abc;timeout=30\r\n\r\n");
+        assert_eq!(lock(&session).timeout(), Duration::from_secs(30));
+
+        let keepalive = lock(&session).keepalive().clone();
+        let toucher = tokio::spawn(async move {
+            sleep(Duration::from_secs(20)).await;
+            keepalive.touch();
+        });
+        assert_eq!(expires(&session).await, (Duration::from_secs(55), Duration::from_secs(35)));
+        toucher.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shorter_session_timeout_is_noticed() {
+        let session = flow_session();
+
+        // the server gives a session with a short timeout while the flow is waiting on the default
+        let updated = session.clone();
+        let server = tokio::spawn(async move {
+            sleep(Duration::from_secs(10)).await;
+            setup(&updated, b"RTSP/1.0 200 OK\r\nCSeq: 1\r\nSession: abc;timeout=1\r\n\r\n");
+        });
+
+        let (elapsed, idle) = expires(&session).await;
+        assert!(elapsed <= Duration::from_secs(10) + CLIENT_SESSION_CHECK_INTERVAL, "expired after {:?}", elapsed);
+        assert_eq!(elapsed, idle);
+        server.await.unwrap();
+    }
+
+    /// a connection whose socket buffers are too small to take a large frame at once
+    /// returns the client's end and a writer running on the stub's, with its media sender and close handle
+    async fn writer_pair() -> (TcpStream, mpsc::Sender<(FrameKind, Bytes)>, oneshot::Sender<Option<Vec<u8>>>, tokio::task::JoinHandle<Result<(usize, bool)>>) {
+        let listening = tokio::net::TcpSocket::new_v4().expect("no socket");
+        listening.set_recv_buffer_size(4096).expect("unable to size receive buffer");
+        listening.bind("127.0.0.1:0".parse().unwrap()).expect("unable to bind");
+        let listener = listening.listen(1).expect("unable to listen");
+        let socket = tokio::net::TcpSocket::new_v4().expect("no socket");
+        socket.set_send_buffer_size(4096).expect("unable to size send buffer");
+        let server = socket.connect(listener.local_addr().unwrap()).await.expect("unable to connect");
+        let (client, _) = listener.accept().await.expect("no connection");
+
+        let (media_tx, media_rx) = mpsc::channel(4);
+        let (close_tx, close_rx) = oneshot::channel();
+        let handle = tokio::spawn(async move {
+            let (_reader, writer) = server.into_split();
+            let (_cp_tx, cp_rx) = mpsc::channel(4);
+            return client_writer(Metrics::new(), media_rx, cp_rx, close_rx, writer).await
+        });
+        return (client, media_tx, close_tx, handle)
+    }
+
+    /// close the flow once the client has started to take the frame, returning all the client gets
+    async fn close_during_frame(frame: &[u8], teardown: &[u8]) -> (Vec<u8>, Result<(usize, bool)>) {
+        let (mut client, media_tx, close_tx, handle) = writer_pair().await;
+        media_tx.send((FrameKind::Rtp, Bytes::copy_from_slice(frame))).await.expect("writer gone");
+        let mut received = vec![0u8; 1024];
+        client.read_exact(&mut received).await.expect("frame not started");
+
+        close_tx.send(Some(teardown.to_vec())).expect("writer gone");
+        client.read_to_end(&mut received).await.expect("connection not shut down");
+        return (received, handle.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn close_writes_final_message_after_frame_in_progress() {
+        let frame: Vec<u8> = (0..512 * 1024).map(|i| i as u8).collect();
+        let teardown = b"TEARDOWN rtsp://camera/stream RTSP/1.0\r\nCSeq: 1\r\n\r\n";
+
+        // the final message follows the whole frame, then the connection is shut down
+        let (received, written) = close_during_frame(&frame, teardown).await;
+        assert_eq!(received.len(), frame.len() + teardown.len());
+        assert!(received[..frame.len()] == frame[..], "frame not written whole");
+        assert_eq!(&received[frame.len()..], &teardown[..]);
+        assert_eq!(written.unwrap(), (frame.len() + teardown.len(), true));
+    }
+
+    #[tokio::test]
+    async fn close_too_far_behind_drops_final_message() {
+        // more is left of the frame than a client may fall behind by
+        let frame: Vec<u8> = (0..2 * 1024 * 1024).map(|i| i as u8).collect();
+        let teardown = b"TEARDOWN rtsp://camera/stream RTSP/1.0\r\nCSeq: 1\r\n\r\n";
+
+        let (received, written) = close_during_frame(&frame, teardown).await;
+        assert!(received == frame, "frame not written whole");
+        assert_eq!(written.unwrap(), (frame.len(), true));
+    }
+}
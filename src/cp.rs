@@ -15,85 +15,270 @@
  */
 
 pub mod msm_cp {
+    #![allow(clippy::all)]
     tonic::include_proto!("msm_cp");
 }
 
 use crate::client::client_outbound;
-use crate::dp::dp_init;
+use crate::dp::{dp_init, DpProxy};
+use crate::lock::{lock, read, write};
+use crate::rtsp::RtspMessage;
+use crate::stub::Stub;
 
 use http::Uri;
 use log::{debug, trace, warn, error};
 
 use self::msm_cp::msm_control_plane_client::MsmControlPlaneClient;
-use self::msm_cp::{Event, Message};
+use self::msm_cp::{DataPlaneConfig, Event, Flow, Message};
 
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
 use std::io::{Error, ErrorKind, Result};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::sleep;
 use tonic::transport::Channel;
 use tonic::Request;
 
-use once_cell::sync::OnceCell;
-static GRPC_TX: OnceCell<mpsc::Sender<Message>> = OnceCell::new();
-static HASH_TX: OnceCell<mpsc::Sender<(HashmapCommand, String, Option<mpsc::Sender<Vec<u8>>>, Option<String>)>> = OnceCell::new();
+use tracing::{debug_span, info_span, Instrument, Span};
+
 const CP_CHANNEL_SIZE: usize = 5;
+const CP_RECONNECT_MIN_BACKOFF: Duration = Duration::from_millis(100);
+const CP_RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 enum HashmapCommand {
     Insert,
     Remove,
+    Close,
     Send,
+    Replay,
 }
 
+/// command, key, optional flow handle, optional data (final message for close, CP data for send)
+/// and the span of the caller, so data sent on to a client stays in its trace
+type HashmapRequest = (HashmapCommand, FlowKey, Option<FlowHandle>, Option<Vec<u8>>, Span);
+
+/// message for the gRPC stream, with a span covering its time in the queue
+type CpQueued = (Message, Span);
+
 impl fmt::Display for HashmapCommand {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
     }
 }
 
+/// Identifies a client flow to the CP by its local and remote socket addresses
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub local: String,
+    pub remote: String,
+}
+
+impl FlowKey {
+    pub fn new(local_addr: String, remote_addr: String) -> Self {
+        FlowKey { local: local_addr, remote: remote_addr }
+    }
+}
+
+impl From<&Message> for FlowKey {
+    /// the typed flow if the CP sent one, otherwise the address strings of older control planes
+    fn from(message: &Message) -> Self {
+        match &message.flow {
+            Some(flow) => FlowKey::new(flow.local.clone(), flow.remote.clone()),
+            None => FlowKey::new(message.local.clone(), message.remote.clone()),
+        }
+    }
+}
+
+impl fmt::Display for FlowKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.local, self.remote)
+    }
+}
+
+/// Lets the CP reach a client flow: its channel for CP data and a handle to close it
+///
+/// CP data is sent with the span it was received in, so the client can link it to the request it answers.
+/// The close handle carries an optional RTSP message (such as a TEARDOWN or ANNOUNCE)
+/// to send to the client before its connection is closed.
+/// The flow's first local RTP port (if it has DP sockets) is announced in ADD, and again whenever flows are replayed.
+#[derive(Debug)]
+pub struct FlowHandle {
+    pub tx: mpsc::Sender<(Vec<u8>, Span)>,
+    pub close: oneshot::Sender<Option<Vec<u8>>>,
+    pub rtp_port: Option<u16>,
+}
+
+/// CP side of a stub: the channel to the current gRPC stream and the flow hash-map
+#[derive(Debug)]
+pub struct ControlPlane {
+    grpc_tx: RwLock<Option<mpsc::Sender<CpQueued>>>,
+    hash_tx: mpsc::Sender<HashmapRequest>,
+    // taken by the connector when it starts the hash-map task
+    hash_rx: Mutex<Option<mpsc::Receiver<HashmapRequest>>>,
+    // connector task running, REGISTER sent and stream up, for health checks
+    running: AtomicBool,
+    registered: AtomicBool,
+    streaming: AtomicBool,
+}
+
+impl ControlPlane {
+    pub fn new() -> Self {
+        let (hash_tx, hash_rx) = mpsc::channel::<HashmapRequest>(1);
+        ControlPlane {
+            grpc_tx: RwLock::new(None),
+            hash_tx,
+            hash_rx: Mutex::new(Some(hash_rx)),
+            running: AtomicBool::new(false),
+            registered: AtomicBool::new(false),
+            streaming: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Default for ControlPlane {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Set (or clear) the channel to the current gRPC stream
+fn cp_set_sender(stub: &Stub, sender: Option<mpsc::Sender<CpQueued>>) {
+    *write(&stub.cp.grpc_tx) = sender;
+}
+
 /// Queue message to send to CP
-pub async fn cp_send(message: Message) -> Result<()> {
-    match GRPC_TX.get() {
+pub async fn cp_send(stub: &Stub, message: Message) -> Result<()> {
+    // clone the sender so the lock isn't held across the await
+    let sender = read(&stub.cp.grpc_tx).clone();
+
+    let span = debug_span!("grpc_send", event = ?Event::from_i32(message.event));
+
+    match sender {
         Some(channel) => {
-            match channel.send(message).await {
+            match channel.send((message, span)).await {
                 Ok(()) => return Ok(()),
-                Err(e) => return Err(Error::new(ErrorKind::Other, e.to_string())),
+                Err(e) => return Err(Error::other(e.to_string())),
             }
         },
-        None => return Err(Error::new(ErrorKind::NotFound, "gRPC handle not initialised")),
+        None => return Err(Error::new(ErrorKind::NotConnected, "gRPC stream not connected")),
+    }
+}
+
+/// Messages waiting to go out on the current gRPC stream
+pub(crate) fn cp_queue_depth(stub: &Stub) -> usize {
+    let sender = read(&stub.cp.grpc_tx).clone();
+
+    match sender {
+        Some(channel) => return channel.max_capacity() - channel.capacity(),
+        None => return 0,
     }
 }
 
+/// Whether the CP connector is still running
+pub(crate) fn cp_running(stub: &Stub) -> bool {
+    return stub.cp.running.load(Ordering::SeqCst)
+}
+
+/// Whether REGISTER has been sent and the gRPC stream is up
+pub(crate) fn cp_ready(stub: &Stub) -> bool {
+    return stub.cp.registered.load(Ordering::SeqCst) && stub.cp.streaming.load(Ordering::SeqCst)
+}
+
+/// message for the CP, with the flow and data in both the typed fields and the strings older control planes read
+/// the payload carries the data exactly, the string only if it is valid UTF-8 and is left empty otherwise
+fn cp_message(event: Event, flow: Option<&FlowKey>, data: Vec<u8>) -> Message {
+    let (local, remote) = match flow {
+        Some(flow) => (flow.local.clone(), flow.remote.clone()),
+        None => (String::new(), String::new()),
+    };
+
+    Message {
+        event: event as i32,
+        flow: flow.map(|flow| Flow { local: flow.local.clone(), remote: flow.remote.clone(), rtp_port: 0 }),
+        data: std::str::from_utf8(&data).map(str::to_string).unwrap_or_default(),
+        payload: data,
+        local,
+        remote,
+        ..Message::default()
+    }
+}
+
+/// RTSP message from the CP, from the typed payload if the CP sent one
+fn cp_message_data(message: Message) -> Vec<u8> {
+    if message.payload.is_empty() {
+        return message.data.into_bytes()
+    }
+    return message.payload
+}
+
+/// DP proxy addresses from a CONFIG message, as a typed config or as the RTP address string of older control planes
+fn cp_config(message: &Message) -> Result<DpProxy> {
+    let config = match &message.config {
+        Some(config) => config,
+        None => {
+            match SocketAddr::from_str(&message.remote) {
+                Ok(rtp) => return Ok(DpProxy::from_rtp(rtp)),
+                Err(e) => return Err(Error::new(ErrorKind::InvalidData, e.to_string())),
+            }
+        },
+    };
+
+    let DataPlaneConfig { rtp_address, rtp_port, rtcp_address, rtcp_port } = config;
+    let rtp = match (IpAddr::from_str(rtp_address), u16::try_from(*rtp_port)) {
+        (Ok(address), Ok(port)) => SocketAddr::new(address, port),
+        _ => return Err(Error::new(ErrorKind::InvalidData, format!("invalid DP RTP address {}:{}", rtp_address, rtp_port))),
+    };
+
+    let mut proxy = DpProxy::from_rtp(rtp);
+    if !rtcp_address.is_empty() {
+        match IpAddr::from_str(rtcp_address) {
+            Ok(address) => proxy.rtcp.set_ip(address),
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, format!("invalid DP RTCP address {}: {}", rtcp_address, e))),
+        }
+    }
+    if *rtcp_port != 0 {
+        match u16::try_from(*rtcp_port) {
+            Ok(port) => proxy.rtcp.set_port(port),
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, format!("invalid DP RTCP port {}: {}", rtcp_port, e))),
+        }
+    }
+
+    return Ok(proxy)
+}
+
 /// Register stub at CP
-pub async fn cp_register() -> Result<()> {
+pub async fn cp_register(stub: &Stub) -> Result<()> {
     trace!("cp register");
-    let message = Message {
-        event: Event::Register as i32,
-        local: String::new(),
-        remote: String::new(),
-        data: String::new(),
-    };
+    return cp_send(stub, cp_message(Event::Register, None, Vec::new())).await
+}
 
-    return cp_send(message).await
+/// ADD message for a client flow, with the flow's local RTP port (if any) typed and as the data older control planes read
+fn cp_add_message(flow: &FlowKey, rtp_port: Option<u16>) -> Message {
+    let data = rtp_port.map(|port| port.to_string()).unwrap_or_default().into_bytes();
+    let mut message = cp_message(Event::Add, Some(flow), data);
+    message.payload.clear();
+    if let Some(flow) = message.flow.as_mut() {
+        flow.rtp_port = rtp_port.map(u32::from).unwrap_or_default();
+    }
+    return message
 }
 
 /// Add client to CP
-pub async fn cp_add(tx: mpsc::Sender::<Vec<u8>>, local_addr: String, remote_addr: String) -> Result<()> {
-    trace!("cp_add for {} {}", local_addr, remote_addr);
-    let message = Message {
-        event: Event::Add as i32,
-        local: local_addr.clone(),
-        remote: remote_addr.clone(),
-        data: String::new(),
-    };
+pub async fn cp_add(stub: &Stub, handle: FlowHandle, flow: &FlowKey) -> Result<()> {
+    trace!("cp_add for {}", flow);
+    let message = cp_add_message(flow, handle.rtp_port);
 
-    match cp_send(message).await {
+    match cp_send(stub, message).await {
         Ok(()) => {
-            match cp_access_hashmap(HashmapCommand::Insert, format!("{} {}", local_addr, remote_addr), Some(tx), None).await {
+            match cp_access_hashmap(stub, HashmapCommand::Insert, flow.clone(), Some(handle), None).await {
                 Ok(()) => return Ok(()),
                 Err(e) => return Err(Error::new(ErrorKind::BrokenPipe, e.to_string())),
             }
@@ -103,46 +288,40 @@ pub async fn cp_add(tx: mpsc::Sender::<Vec<u8>>, local_addr: String, remote_addr
 }
 
 /// Delete client from CP
-pub async fn cp_delete(local_addr: String, remote_addr: String) -> Result<()> {
-    trace!("cp delete for {} {}", local_addr, remote_addr);
-    let message = Message {
-        event: Event::Delete as i32,
-        local: local_addr.clone(),
-        remote: remote_addr.clone(),
-        data: String::new(),
-    };
+/// the flow leaves the flow table even if the CP can't be told, so it isn't replayed to the CP once it is back
+pub async fn cp_delete(stub: &Stub, flow: &FlowKey) -> Result<()> {
+    trace!("cp delete for {}", flow);
+    let message = cp_message(Event::Delete, Some(flow), Vec::new());
+
+    let sent = cp_send(stub, message).await;
+    match cp_access_hashmap(stub, HashmapCommand::Remove, flow.clone(), None, None).await {
+        Ok(()) => trace!("flow {} removed from flow table", flow),
+        Err(e) => return Err(Error::new(ErrorKind::BrokenPipe, e.to_string())),
+    }
 
-    match cp_send(message).await {
-        Ok(()) => {
-            match cp_access_hashmap(HashmapCommand::Remove, format!("{} {}", local_addr, remote_addr), None, None).await {
-                Ok(()) => return Ok(()),
-                Err(e) => return Err(Error::new(ErrorKind::BrokenPipe, e.to_string())),
-            }
-        },  
+    match sent {
+        Ok(()) => return Ok(()),
         Err(e) => return Err(Error::new(ErrorKind::BrokenPipe, e.to_string())),
     }
 }
 
-/// Send data message to CP
-pub async fn cp_data(local_addr: String, remote_addr: String, message_string: String) -> Result<()> {
-    trace!("CP message from client {} {}, data {}", local_addr, remote_addr, message_string);
-    let message = Message {
-        event: Event::Data as i32,
-        local: local_addr,
-        remote: remote_addr,
-        data: message_string,
-    };
+/// Send data message to CP, with the RTSP session it belongs to if the client gave one
+/// the data goes to the CP exactly as the client sent it
+pub async fn cp_data(stub: &Stub, flow: &FlowKey, data: Vec<u8>, session: Option<&str>) -> Result<()> {
+    trace!("CP message from client {}, data {}", flow, String::from_utf8_lossy(&data));
+    let mut message = cp_message(Event::Data, Some(flow), data);
+    message.session_id = session.unwrap_or_default().to_string();
 
-    return cp_send(message).await
+    return cp_send(stub, message).instrument(info_span!("cp_data")).await
 }
 
 /// hashmap owner
-async fn cp_hashmap(mut chan_rx: mpsc::Receiver<(HashmapCommand, String, Option<mpsc::Sender<Vec<u8>>>, Option<String>)>) -> () {
-    let mut channels = HashMap::<String, mpsc::Sender<Vec<u8>>>::new();
+async fn cp_hashmap(stub: Arc<Stub>, mut chan_rx: mpsc::Receiver<HashmapRequest>) -> () {
+    let mut channels = HashMap::<FlowKey, FlowHandle>::new();
 
     loop {
         match chan_rx.recv().await {
-            Some((command, key, optional_value, optional_data)) => {
+            Some((command, key, optional_value, optional_data, span)) => {
                 match command {
                     HashmapCommand::Insert => {
                         match optional_value {
@@ -164,15 +343,26 @@ async fn cp_hashmap(mut chan_rx: mpsc::Receiver<(HashmapCommand, String, Option<
                             None => { warn!("key {} not present!", key) },
                         }
                     },
+                    HashmapCommand::Close => {
+                        match channels.remove(&key) {
+                            Some(handle) => {
+                                match handle.close.send(optional_data) {
+                                    Ok(()) => debug!("key {} closed", key),
+                                    Err(_data) => debug!("key {} already closing", key),
+                                }
+                            },
+                            None => { warn!("key {} not present!", key) },
+                        }
+                    },
                     HashmapCommand::Send => {
                         trace!("sending data to key {}", key);
                         match channels.get(&key) {
-                            Some(value_ref) => { 
+                            Some(handle) => {
                                 trace!("found channel for key {}",  key);
                                 match optional_data {
                                     Some(data) => {
-                                        trace!("Received from CP: {}", data.to_string());
-                                        match value_ref.send(data.into_bytes()).await {
+                                        trace!("Received from CP: {}", String::from_utf8_lossy(&data));
+                                        match handle.tx.send((data, span)).await {
                                             Ok(()) => { debug!("sent CP data to channel") },
                                             Err(_e) => { warn!("unable to send CP data for key {}", key) },
                                         }
@@ -186,6 +376,18 @@ async fn cp_hashmap(mut chan_rx: mpsc::Receiver<(HashmapCommand, String, Option<
                             None => { warn!("key {} not present!", key) },
                         }
                     },
+                    HashmapCommand::Replay => {
+                        debug!("replaying {} flows to CP", channels.len());
+                        for (flow, handle) in channels.iter() {
+                            match cp_send(&stub, cp_add_message(flow, handle.rtp_port)).await {
+                                Ok(()) => trace!("replayed flow {}", flow),
+                                Err(e) => {
+                                    warn!("unable to replay flows to CP: {}", e);
+                                    break
+                                },
+                            }
+                        }
+                    },
                 }
             },
             None => {
@@ -197,48 +399,68 @@ async fn cp_hashmap(mut chan_rx: mpsc::Receiver<(HashmapCommand, String, Option<
 }
 
 /// Send to hashmap owner
-async fn cp_access_hashmap(command: HashmapCommand, key: String, optional_channel: Option<mpsc::Sender<Vec<u8>>>, optional_data: Option<String>) -> Result<()> {
-    match HASH_TX.get() {
-        Some(channel) => {
-            trace!("sending command {} to hashmap for key {}", command.to_string(), key);
-            match channel.send((command, key, optional_channel, optional_data)).await {
-                Ok(()) => return Ok(()),
-                Err(e) => return Err(Error::new(ErrorKind::BrokenPipe, e.to_string())),
-            }
-        },
-        None => return Err(Error::new(ErrorKind::NotFound, "hashmap handle not initlialised")),
+async fn cp_access_hashmap(stub: &Stub, command: HashmapCommand, key: FlowKey, optional_handle: Option<FlowHandle>, optional_data: Option<Vec<u8>>) -> Result<()> {
+    trace!("sending command {} to hashmap for key {}", command, key);
+    match stub.cp.hash_tx.send((command, key, optional_handle, optional_data, Span::current())).await {
+        Ok(()) => return Ok(()),
+        Err(e) => return Err(Error::new(ErrorKind::BrokenPipe, e.to_string())),
     }
 }
 
+/// Pass an RTSP message to a client as though the CP had sent it, for requests the stub answers itself
+pub(crate) async fn cp_reply(stub: &Stub, key: &FlowKey, data: Vec<u8>) -> Result<()> {
+    return cp_access_hashmap(stub, HashmapCommand::Send, key.clone(), None, Some(data)).await
+}
+
 /// Add flow from CP
-async fn cp_add_flow(remote_addr: String) -> Result<()> {
+async fn cp_add_flow(stub: &Arc<Stub>, remote_addr: String) -> Result<()> {
     trace!("CP add flow for {}", remote_addr);
-    match client_outbound(remote_addr.clone()).await {
+    match client_outbound(stub.clone(), remote_addr.clone()).await {
         // connected to client so add it to CP
         Ok(()) => return Ok(()),
         Err(e) => return Err(e),
     }
 }
 
-/// Delete flow from CP
-async fn cp_del_flow(key: String) -> Result<()> {
-    return cp_access_hashmap(HashmapCommand::Remove, key, None, None).await;
+/// Delete flow from CP, closing the client connection
+/// any DELETE data is an RTSP message (such as a TEARDOWN or ANNOUNCE) for the client first
+async fn cp_del_flow(stub: &Stub, key: FlowKey, data: Vec<u8>) -> Result<()> {
+    let message = if data.is_empty() {
+        None
+    } else {
+        match RtspMessage::parse(&data) {
+            Ok(parsed) => {
+                debug!("sending RTSP {} to client {} before closing", parsed, key);
+                Some(data)
+            },
+            Err(e) => {
+                warn!("closing client {} without invalid RTSP message from CP: {}", key, e);
+                None
+            },
+        }
+    };
+
+    return cp_access_hashmap(stub, HashmapCommand::Close, key, None, message).await;
 }
 
 /// Received data from CP
-async fn cp_data_rcvd(key: String, data: String) -> Result<()> {
-    debug!("Data {} received from CP for flow {}", data, key);
-    return cp_access_hashmap(HashmapCommand::Send, key, None, Some(data)).await;
+async fn cp_data_rcvd(stub: &Stub, key: FlowKey, data: Vec<u8>) -> Result<()> {
+    debug!("Data {} received from CP for flow {}", String::from_utf8_lossy(&data), key);
+    return cp_access_hashmap(stub, HashmapCommand::Send, key, None, Some(data)).await;
 }
 
 /// Run bidirectional streaming RPC
-async fn cp_stream(handle: &mut MsmControlPlaneClient<Channel>, mut grpc_rx: mpsc::Receiver<Message>) -> Result<()> {
+async fn cp_stream(stub: &Arc<Stub>, handle: &mut MsmControlPlaneClient<Channel>, mut grpc_rx: mpsc::Receiver<CpQueued>, backoff: &mut Duration) -> Result<()> {
 
     let requests = async_stream::stream! {
         loop {
             trace!("request for CP");
             match grpc_rx.recv().await {
-                Some(message) => yield(message),
+                Some((message, span)) => {
+                    // the message has made it to the stream, which ends its queueing span
+                    drop(span);
+                    yield(message)
+                },
                 None => {
                     error!("no message received from CP");
                     return
@@ -251,6 +473,10 @@ async fn cp_stream(handle: &mut MsmControlPlaneClient<Channel>, mut grpc_rx: mps
         Ok(responses) => {
             let mut inbound = responses.into_inner();
 
+            // stream is up so start any later reconnect from the shortest backoff
+            *backoff = CP_RECONNECT_MIN_BACKOFF;
+            stub.cp.streaming.store(true, Ordering::SeqCst);
+
             loop {
                 match inbound.message().await {
                     Ok(option) => {
@@ -265,10 +491,10 @@ async fn cp_stream(handle: &mut MsmControlPlaneClient<Channel>, mut grpc_rx: mps
                                     },
                                     Some(Event::Config) => {
                                         trace!("config from CP");
-                                        match SocketAddr::from_str(&message.remote) {
-                                            Ok(socket_addr) => {
-                                                match dp_init(socket_addr).await {
-                                                    Ok(()) => debug!("Connected to DP {}", socket_addr),
+                                        match cp_config(&message) {
+                                            Ok(proxy) => {
+                                                match dp_init(stub, proxy).await {
+                                                    Ok(()) => debug!("Connected to DP {}", proxy),
                                                     Err(e) => error!("Error connecting to DP: {}", e),
                                                 }
                                             },
@@ -277,7 +503,7 @@ async fn cp_stream(handle: &mut MsmControlPlaneClient<Channel>, mut grpc_rx: mps
                                     },
                                     Some(Event::Request) => {
                                         trace!("Request to add from CP");
-                                        match cp_add_flow(message.remote).await {
+                                        match cp_add_flow(stub, FlowKey::from(&message).remote).await {
                                             Ok(()) => debug!("CP added flow"),
                                             Err(e) => return Err(e),
                                         }
@@ -288,14 +514,16 @@ async fn cp_stream(handle: &mut MsmControlPlaneClient<Channel>, mut grpc_rx: mps
                                     },
                                     Some(Event::Delete) => {
                                         trace!("delete from CP");
-                                        match cp_del_flow(format!("{}{}", message.local, message.remote)).await {
+                                        match cp_del_flow(stub, FlowKey::from(&message), cp_message_data(message)).await {
                                             Ok(()) => debug!("CP deleted flow"),
                                             Err(e) => return Err(e),
                                         }
                                     },
                                     Some(Event::Data) => {
                                         trace!("data from CP");
-                                        match cp_data_rcvd(format!("{} {}", message.local, message.remote), message.data).await {
+                                        let key = FlowKey::from(&message);
+                                        let span = info_span!("cp_data_rcvd", local = %key.local, remote = %key.remote, session = %message.session_id);
+                                        match cp_data_rcvd(stub, key, cp_message_data(message)).instrument(span).await {
                                             Ok(()) => debug!("data received from CP"),
                                             Err(e) => return Err(e),
                                         }
@@ -303,10 +531,10 @@ async fn cp_stream(handle: &mut MsmControlPlaneClient<Channel>, mut grpc_rx: mps
                                     None => return Err(Error::new(ErrorKind::InvalidInput, "Invalid event value")),
                                 }
                             },
-                            None => return Err(Error::new(ErrorKind::Other, "no message")),
+                            None => return Err(Error::other("no message")),
                         }
                     },
-                    Err(e) => return Err(Error::new(ErrorKind::Other, e.to_string())),
+                    Err(e) => return Err(Error::other(e.to_string())),
                 }
             }
         },
@@ -314,47 +542,208 @@ async fn cp_stream(handle: &mut MsmControlPlaneClient<Channel>, mut grpc_rx: mps
     }
 }
 
-/// CP connector
-pub async fn cp_connector(uri: Uri) -> Result<()> {
+/// Connect to the CP, register, replay live flows and handle messages until the stream ends
+async fn cp_session(stub: &Arc<Stub>, uri: Uri, backoff: &mut Duration) -> Result<()> {
 
     debug!("connecting to gRPC CP");
-    
+
     // Connect to gRPC CP
     match MsmControlPlaneClient::connect(uri).await {
 
         Ok(mut handle) => {
 
             // Now create channel to receive messages from CP functions
-            let (grpc_tx, grpc_rx) = mpsc::channel::<Message>(CP_CHANNEL_SIZE);
+            // anything still queued for a previous stream is dropped with its receiver
+            let (grpc_tx, grpc_rx) = mpsc::channel::<CpQueued>(CP_CHANNEL_SIZE);
+            cp_set_sender(stub, Some(grpc_tx));
 
-            // init the channel sender handle
-            match GRPC_TX.set(grpc_tx) {
+            // Now register the stub with the CP
+            match cp_register(stub).await {
                 Ok(()) => {
+                    stub.cp.registered.store(true, Ordering::SeqCst);
 
-                    // Now register the stub with the CP
-                    match cp_register().await {
+                    // re-announce any flows that outlived a previous stream
+                    match cp_access_hashmap(stub, HashmapCommand::Replay, FlowKey::default(), None, None).await {
                         Ok(()) => {
-
-                            // create channel to access hash-map entries
-                            let (hash_tx, hash_rx) = mpsc::channel::<(HashmapCommand, String, Option<mpsc::Sender<Vec<u8>>>, Option<String>)>(1);
-
-                            match HASH_TX.set(hash_tx) {
-                                Ok(()) => {
-                                    // start the hash-map task
-                                    tokio::spawn(async move { cp_hashmap(hash_rx).await });
-
-                                    // now start handling messages
-                                    return cp_stream(&mut handle, grpc_rx).await
-                                },
-                                _ => return Err(Error::new(ErrorKind::AlreadyExists, "Hashmap OnceCell already set")),
-                            }
+                            // now start handling messages
+                            return cp_stream(stub, &mut handle, grpc_rx, backoff).await
                         },
                         Err(e) => return Err(e),
                     }
                 },
-                _ => return Err(Error::new(ErrorKind::AlreadyExists, "gRPC OnceCell already set")),
+                Err(e) => return Err(e),
             }
         },
         Err(e) => return Err(Error::new(ErrorKind::NotConnected, e.to_string())),
     }
-}
\ No newline at end of file
+}
+
+/// CP connector
+pub async fn cp_connector(stub: Arc<Stub>, uri: Uri) -> Result<()> {
+
+    // the hash-map outlives any one gRPC stream so flows can be replayed after a reconnect
+    let hash_rx = lock(&stub.cp.hash_rx).take();
+
+    match hash_rx {
+        Some(hash_rx) => {
+            // start the hash-map task
+            let hashmap_stub = stub.clone();
+            tokio::spawn(async move { cp_hashmap(hashmap_stub, hash_rx).await });
+        },
+        None => return Err(Error::new(ErrorKind::AlreadyExists, "CP connector already running for this stub")),
+    }
+
+    stub.cp.running.store(true, Ordering::SeqCst);
+    let result = cp_connect_loop(&stub, uri).await;
+    stub.cp.running.store(false, Ordering::SeqCst);
+    return result
+}
+
+/// connect and reconnect to the CP until the stub shuts down
+async fn cp_connect_loop(stub: &Arc<Stub>, uri: Uri) -> Result<()> {
+    let mut backoff = CP_RECONNECT_MIN_BACKOFF;
+
+    loop {
+        let result = cp_session(stub, uri.clone(), &mut backoff).await;
+
+        // stop queueing messages for a stream that has gone
+        cp_set_sender(stub, None);
+        stub.cp.registered.store(false, Ordering::SeqCst);
+        stub.cp.streaming.store(false, Ordering::SeqCst);
+
+        if stub.is_shutting_down() {
+            debug!("CP stream closed for shutdown");
+            return Ok(())
+        }
+
+        match result {
+            Ok(()) => warn!("CP stream closed"),
+            Err(e) => error!("CP stream failed: {}", e),
+        }
+
+        warn!("reconnecting to CP in {:?}", backoff);
+        tokio::select! {
+            _ = sleep(backoff) => {
+                trace!("reconnecting to CP");
+                stub.metrics.cp_reconnects.inc();
+            },
+            _ = stub.shutdown_requested() => {
+                debug!("stub shutting down, no longer reconnecting to CP");
+                return Ok(())
+            },
+        }
+        backoff = std::cmp::min(backoff * 2, CP_RECONNECT_MAX_BACKOFF);
+    }
+}
+
+/// Close the gRPC stream once anything already queued (such as the DELETEs for closed flows) has been sent
+pub fn cp_close(stub: &Stub) {
+    debug!("closing CP stream");
+    cp_set_sender(stub, None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(local: &str, remote: &str) -> Flow {
+        Flow { local: local.to_string(), remote: remote.to_string(), rtp_port: 0 }
+    }
+
+    fn config(rtp_address: &str, rtp_port: u32, rtcp_address: &str, rtcp_port: u32) -> Message {
+        let config = DataPlaneConfig { rtp_address: rtp_address.to_string(), rtp_port, rtcp_address: rtcp_address.to_string(), rtcp_port };
+        Message { event: Event::Config as i32, config: Some(config), ..Message::default() }
+    }
+
+    fn proxy(rtp: &str, rtcp: &str) -> DpProxy {
+        DpProxy { rtp: rtp.parse().unwrap(), rtcp: rtcp.parse().unwrap() }
+    }
+
+    #[test]
+    fn flow_key_prefers_the_typed_flow() {
+        let legacy = Message { local: "10.0.0.1:554".to_string(), remote: "10.0.0.2:40000".to_string(), ..Message::default() };
+        assert_eq!(FlowKey::from(&legacy), FlowKey::new("10.0.0.1:554".to_string(), "10.0.0.2:40000".to_string()));
+
+        let typed = Message { flow: Some(flow("10.0.0.3:554", "10.0.0.4:40000")), ..legacy };
+        assert_eq!(FlowKey::from(&typed), FlowKey::new("10.0.0.3:554".to_string(), "10.0.0.4:40000".to_string()));
+    }
+
+    #[test]
+    fn message_data_prefers_the_payload() {
+        let legacy = Message { data: "OPTIONS * RTSP/1.0\r\n\r\n".to_string(), ..Message::default() };
+        assert_eq!(cp_message_data(legacy.clone()), b"OPTIONS * RTSP/1.0\r\n\r\n".to_vec());
+
+        let typed = Message { payload: vec![0xff, 0x00], ..legacy };
+        assert_eq!(cp_message_data(typed), vec![0xff, 0x00]);
+
+        assert!(cp_message_data(Message::default()).is_empty());
+    }
+
+    #[test]
+    fn message_data_is_only_sent_as_a_string_if_utf8() {
+        let key = FlowKey::new("10.0.0.1:554".to_string(), "10.0.0.2:40000".to_string());
+
+        let text = cp_message(Event::Data, Some(&key), b"OPTIONS * RTSP/1.0\r\n\r\n".to_vec());
+        assert_eq!(text.payload, b"OPTIONS * RTSP/1.0\r\n\r\n".to_vec());
+        assert_eq!(text.data, "OPTIONS * RTSP/1.0\r\n\r\n");
+
+        // a binary body reaches the CP unchanged in the payload, with no mangled string beside it
+        let binary = b"ANNOUNCE rtsp://camera/stream RTSP/1.0\r\nCSeq: 2\r\nContent-Length: 3\r\n\r\n\x00\xff\xfe".to_vec();
+        let message = cp_message(Event::Data, Some(&key), binary.clone());
+        assert_eq!(message.payload, binary);
+        assert_eq!(message.data, "");
+        assert_eq!((message.local.as_str(), message.remote.as_str()), ("10.0.0.1:554", "10.0.0.2:40000"));
+        assert_eq!(cp_message_data(message), binary);
+    }
+
+    #[test]
+    fn add_message_carries_the_rtp_port() {
+        let key = FlowKey::new("10.0.0.1:554".to_string(), "10.0.0.2:40000".to_string());
+
+        let message = cp_add_message(&key, Some(5000));
+        assert_eq!(Event::from_i32(message.event), Some(Event::Add));
+        assert_eq!((message.data.as_str(), message.payload.is_empty()), ("5000", true));
+        assert_eq!(message.flow.map(|flow| flow.rtp_port), Some(5000));
+
+        let message = cp_add_message(&key, None);
+        assert_eq!((message.data.as_str(), message.payload.is_empty()), ("", true));
+        assert_eq!(message.flow.map(|flow| flow.rtp_port), Some(0));
+    }
+
+    #[test]
+    fn config_defaults_rtcp_to_the_port_above_rtp() {
+        assert_eq!(cp_config(&config("10.0.0.5", 8050, "", 0)).unwrap(), proxy("10.0.0.5:8050", "10.0.0.5:8051"));
+    }
+
+    #[test]
+    fn config_overrides_rtcp() {
+        assert_eq!(cp_config(&config("10.0.0.5", 8050, "", 9000)).unwrap(), proxy("10.0.0.5:8050", "10.0.0.5:9000"));
+        assert_eq!(cp_config(&config("10.0.0.5", 8050, "10.0.0.6", 0)).unwrap(), proxy("10.0.0.5:8050", "10.0.0.6:8051"));
+        assert_eq!(cp_config(&config("10.0.0.5", 8050, "::1", 9000)).unwrap(), proxy("10.0.0.5:8050", "[::1]:9000"));
+    }
+
+    #[test]
+    fn config_from_legacy_remote() {
+        let legacy = Message { event: Event::Config as i32, remote: "10.0.0.5:8050".to_string(), ..Message::default() };
+        assert_eq!(cp_config(&legacy).unwrap(), proxy("10.0.0.5:8050", "10.0.0.5:8051"));
+
+        let invalid = Message { remote: "10.0.0.5".to_string(), ..legacy };
+        assert_eq!(cp_config(&invalid).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        // a typed config wins over the string
+        let both = Message { remote: "10.0.0.5:8050".to_string(), ..config("10.0.0.7", 6000, "", 0) };
+        assert_eq!(cp_config(&both).unwrap(), proxy("10.0.0.7:6000", "10.0.0.7:6001"));
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        for message in [
+            config("dp.example", 8050, "", 0),
+            config("10.0.0.5", 65536, "", 0),
+            config("10.0.0.5", 8050, "dp.example", 0),
+            config("10.0.0.5", 8050, "", 65536),
+        ] {
+            assert_eq!(cp_config(&message).unwrap_err().kind(), ErrorKind::InvalidData, "{:?}", message.config);
+        }
+    }
+}
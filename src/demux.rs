@@ -0,0 +1,255 @@
+/*
+ * Copyright (c) 2022 Cisco and/or its affiliates.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at:
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::rtsp::{rtsp_message_len, rtsp_skip_empty_lines};
+
+use bytes::{Buf, BytesMut};
+
+use log::trace;
+
+use std::io::{Error, Result};
+
+use tokio_util::codec::Decoder;
+
+/// interleaved frames start with '$', a channel byte and a 16-bit length
+pub(crate) const INTERLEAVED_MAGIC: u8 = 0x24;
+pub(crate) const INTERLEAVED_HEADER_SIZE: usize = 4;
+
+/// read buffer is grown by at least this much before each read
+const DEMUX_READ_SIZE: usize = 262168;
+
+/// A single unit of client data
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientMessage {
+    /// RTP/RTCP data interleaved on the RTSP connection
+    Interleaved { channel: u8, data: BytesMut },
+    /// complete RTSP message (start line, headers and body)
+    Rtsp(BytesMut),
+}
+
+/// Splits the client byte stream into interleaved frames and RTSP messages,
+/// holding on to any partial frame or message until the rest arrives
+#[derive(Debug, Default)]
+pub struct ClientDemux {
+    buf: BytesMut,
+}
+
+impl ClientDemux {
+    pub fn new() -> Self {
+        ClientDemux { buf: BytesMut::new() }
+    }
+
+    /// buffer to read new client data into
+    pub fn read_buf(&mut self) -> &mut BytesMut {
+        self.buf.reserve(DEMUX_READ_SIZE);
+        &mut self.buf
+    }
+
+    /// bytes held waiting for the rest of a frame or message
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// whether the buffered data is the start of an interleaved frame
+    pub fn partial_frame(&self) -> bool {
+        return self.buf.first() == Some(&INTERLEAVED_MAGIC)
+    }
+
+    /// take the next complete frame or message, or None if more data is needed
+    pub fn next_message(&mut self) -> Result<Option<ClientMessage>> {
+        return ClientCodec.decode(&mut self.buf)
+    }
+}
+
+/// Decoder for the client byte stream, splitting interleaved frames and RTSP
+/// messages off the front of the buffer without copying them
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ClientCodec;
+
+impl Decoder for ClientCodec {
+    type Item = ClientMessage;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ClientMessage>> {
+        let skipped = rtsp_skip_empty_lines(src);
+        src.advance(skipped);
+
+        if src.is_empty() {
+            return Ok(None)
+        }
+
+        if src[0] == INTERLEAVED_MAGIC {
+            if src.len() < INTERLEAVED_HEADER_SIZE {
+                return Ok(None)
+            }
+
+            let channel = src[1];
+            let length = (src[2] as usize) << 8 | src[3] as usize;
+
+            trace!("interleaved frame on channel {} with length {}", channel, length);
+
+            if src.len() < length + INTERLEAVED_HEADER_SIZE {
+                trace!("have {} bytes of {} byte frame", src.len(), length + INTERLEAVED_HEADER_SIZE);
+                src.reserve(length + INTERLEAVED_HEADER_SIZE - src.len());
+                return Ok(None)
+            }
+
+            src.advance(INTERLEAVED_HEADER_SIZE);
+            let data = src.split_to(length);
+            return Ok(Some(ClientMessage::Interleaved { channel, data }))
+        }
+
+        match rtsp_message_len(src) {
+            Ok(Some(length)) => return Ok(Some(ClientMessage::Rtsp(src.split_to(length)))),
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: &[u8] = b"OPTIONS rtsp://camera/stream RTSP/1.0\r\nCSeq: 1\r\n\r\n";
+    const ANNOUNCE: &[u8] = b"ANNOUNCE rtsp://camera/stream RTSP/1.0\r\nCSeq: 2\r\nContent-Length: 5\r\n\r\nv=0\r\n";
+    const FRAME: &[u8] = b"$\x01\x00\x03abc";
+
+    fn rtsp(message: &[u8]) -> ClientMessage {
+        ClientMessage::Rtsp(BytesMut::from(message))
+    }
+
+    fn frame(channel: u8, data: &[u8]) -> ClientMessage {
+        ClientMessage::Interleaved { channel, data: BytesMut::from(data) }
+    }
+
+    /// feed each read to the demuxer in turn, taking every message it can after each one
+    fn feed(mut demux: ClientDemux, reads: &[&[u8]]) -> (ClientDemux, Vec<ClientMessage>) {
+        let mut messages = Vec::new();
+        for read in reads {
+            demux.read_buf().extend_from_slice(read);
+            while let Some(message) = demux.next_message().expect("demux failed") {
+                messages.push(message);
+            }
+        }
+        return (demux, messages)
+    }
+
+    fn demux(reads: &[&[u8]]) -> (ClientDemux, Vec<ClientMessage>) {
+        feed(ClientDemux::new(), reads)
+    }
+
+    #[test]
+    fn message_then_frame_in_one_read() {
+        let (demux, messages) = demux(&[&[OPTIONS, FRAME].concat()]);
+        assert_eq!(messages, vec![rtsp(OPTIONS), frame(1, b"abc")]);
+        assert_eq!(demux.buffered(), 0);
+    }
+
+    #[test]
+    fn frame_then_message_in_one_read() {
+        let (demux, messages) = demux(&[&[FRAME, ANNOUNCE, FRAME].concat()]);
+        assert_eq!(messages, vec![frame(1, b"abc"), rtsp(ANNOUNCE), frame(1, b"abc")]);
+        assert_eq!(demux.buffered(), 0);
+    }
+
+    #[test]
+    fn frame_split_across_reads() {
+        for split in 1..FRAME.len() {
+            let mut demux = ClientDemux::new();
+            demux.read_buf().extend_from_slice(&FRAME[..split]);
+            assert_eq!(demux.next_message().unwrap(), None, "split at {}", split);
+            assert!(demux.partial_frame());
+            assert_eq!(demux.buffered(), split);
+
+            demux.read_buf().extend_from_slice(&FRAME[split..]);
+            demux.read_buf().extend_from_slice(OPTIONS);
+            assert_eq!(demux.next_message().unwrap(), Some(frame(1, b"abc")));
+            assert!(!demux.partial_frame());
+            assert_eq!(demux.next_message().unwrap(), Some(rtsp(OPTIONS)));
+            assert_eq!(demux.next_message().unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn message_split_across_reads() {
+        for split in 1..ANNOUNCE.len() {
+            let (demux, messages) = demux(&[&ANNOUNCE[..split]]);
+            assert!(messages.is_empty(), "split at {}", split);
+            assert!(!demux.partial_frame());
+
+            let (_, messages) = feed(demux, &[&ANNOUNCE[split..], FRAME]);
+            assert_eq!(messages, vec![rtsp(ANNOUNCE), frame(1, b"abc")], "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn blank_lines_between_messages_are_skipped() {
+        let (demux, messages) = demux(&[b"\r\n", OPTIONS, b"\r\n\r\n", FRAME, b"\n", ANNOUNCE, b"\r\n"]);
+        assert_eq!(messages, vec![rtsp(OPTIONS), frame(1, b"abc"), rtsp(ANNOUNCE)]);
+        assert_eq!(demux.buffered(), 0);
+        assert!(!demux.partial_frame());
+    }
+
+    #[test]
+    fn oversized_message_is_an_error() {
+        let mut demux = ClientDemux::new();
+        demux.read_buf().extend_from_slice(b"ANNOUNCE rtsp://camera/stream RTSP/1.0\r\nContent-Length: 99999999\r\n\r\n");
+        assert!(demux.next_message().is_err());
+    }
+
+    #[test]
+    fn codec_splits_back_to_back_frames_in_place() {
+        let mut src = BytesMut::from(&b"$\x00\x00\x02ab$\x01\x00\x03cde$\x02\x00\x01f"[..]);
+        let start = src.as_ptr() as usize;
+
+        let mut frames = Vec::new();
+        while let Some(message) = ClientCodec.decode(&mut src).unwrap() {
+            match message {
+                ClientMessage::Interleaved { channel, data } => frames.push((channel, data.as_ptr() as usize - start, data.to_vec())),
+                ClientMessage::Rtsp(message) => panic!("decoded RTSP {:?}", message),
+            }
+        }
+
+        // each payload is a view of the read buffer, straight after its header
+        assert_eq!(frames, vec![(0, 4, b"ab".to_vec()), (1, 10, b"cde".to_vec()), (2, 17, b"f".to_vec())]);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn codec_waits_for_split_header() {
+        let mut src = BytesMut::new();
+        for byte in b"$\x03\x00" {
+            src.extend_from_slice(&[*byte]);
+            assert_eq!(ClientCodec.decode(&mut src).unwrap(), None);
+        }
+        src.extend_from_slice(b"\x02");
+        assert_eq!(ClientCodec.decode(&mut src).unwrap(), None);
+        assert_eq!(src.len(), 4);
+
+        src.extend_from_slice(b"xy$");
+        assert_eq!(ClientCodec.decode(&mut src).unwrap(), Some(frame(3, b"xy")));
+        assert_eq!(&src[..], b"$");
+    }
+
+    #[test]
+    fn codec_decodes_zero_length_frame() {
+        let mut src = BytesMut::from(&b"$\x05\x00\x00$\x06\x00\x01z"[..]);
+        assert_eq!(ClientCodec.decode(&mut src).unwrap(), Some(frame(5, b"")));
+        assert_eq!(ClientCodec.decode(&mut src).unwrap(), Some(frame(6, b"z")));
+        assert_eq!(ClientCodec.decode(&mut src).unwrap(), None);
+    }
+}
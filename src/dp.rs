@@ -14,222 +14,724 @@
  * limitations under the License.
  */
 
-use async_recursion::async_recursion;
+use crate::lock::{get_mut, lock, read, write};
+use crate::media::rtcp_is_report;
+use crate::metrics::{Metrics, MEDIA_RTCP, MEDIA_RTP, TO_CLIENT, TO_DP};
+use crate::pool::FramePool;
+use crate::queue::FrameKind;
+use crate::session::Keepalive;
+use crate::stub::Stub;
 
-use log::{debug, trace, warn};
+use bytes::Bytes;
 
+use log::{debug, info, trace, warn};
+
+use std::collections::HashMap;
+use std::fmt;
 use std::io::{Error, ErrorKind, Result};
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use tokio::net::UdpSocket;
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+
+use tracing::Instrument;
+
+/// number of flows (blocks of local ports) tried when allocating sockets for a flow
+const DP_MAX_FLOWS: u16 = 1024;
+
+/// interleaved channels (and so local and DP ports) reserved for each flow
+/// channels N and N + 1 of a flow use local ports flow base + N and N + 1, and the DP RTP and RTCP ports + N
+const DP_CHANNELS_PER_FLOW: u16 = 8;
+
+/// channel of the track bound when the flow is created, which holds the flow's port block until the flow is dropped
+const DP_BASE_CHANNEL: u8 = 0;
+
+/// DP proxy addresses for RTP and RTCP on channels 0 and 1, further channels use the ports above
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DpProxy {
+    pub rtp: SocketAddr,
+    pub rtcp: SocketAddr,
+}
+
+impl DpProxy {
+    /// RTCP on the port above RTP, as older control planes expect
+    pub fn from_rtp(rtp: SocketAddr) -> Self {
+        let rtcp = SocketAddr::new(rtp.ip(), rtp.port().wrapping_add(1));
+        DpProxy { rtp, rtcp }
+    }
+}
+
+impl fmt::Display for DpProxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RTP {} RTCP {}", self.rtp, self.rtcp)
+    }
+}
 
-use once_cell::sync::OnceCell;
-static RTP_TX: OnceCell<UdpSocket> = OnceCell::new();
-static RTCP_TX: OnceCell<UdpSocket> = OnceCell::new();
+/// DP side of a stub: the DP proxy addresses from CONFIG and the base of the local RTP/RTCP port range
+#[derive(Debug)]
+pub struct DataPlane {
+    proxy: RwLock<Option<DpProxy>>,
+    rtp_port: u16,
+}
 
-/// init the UDP sockets to send to DP
-pub async fn dp_init(proxy_rtp: SocketAddr) -> Result <()> {
+impl DataPlane {
+    pub fn new(rtp_port: u16) -> Self {
+        DataPlane {
+            proxy: RwLock::new(None),
+            rtp_port,
+        }
+    }
+}
 
-    trace!("RTP proxy is {}", proxy_rtp);
+/// attempts at finding a free even/odd pair of client-facing ports
+const DP_CLIENT_PORT_ATTEMPTS: usize = 64;
 
-    let rtp_port = envmnt::get_u16("LOCAL_RTP_PORT", 8050);
-    let rtcp_port = rtp_port + 1;
+/// RTP/RTCP sockets for one interleaved channel pair, connected to the DP proxy
+#[derive(Debug)]
+pub struct DpTrack {
+    rtp: UdpSocket,
+    rtcp: UdpSocket,
+    rtp_channel: u8,
+    rtcp_channel: u8,
+    // set when the client asked for RTP over UDP rather than interleaved
+    client: Option<DpClientUdp>,
+}
+
+/// client-facing sockets for a track carried over UDP, connected to the client's ports
+#[derive(Debug)]
+struct DpClientUdp {
+    rtp: UdpSocket,
+    rtcp: UdpSocket,
+    rtp_port: u16,
+}
+
+/// DP state for one flow: a track per interleaved channel pair negotiated in SETUP
+///
+/// Each flow gets its own block of local ports so media from the DP only reaches the client it belongs to.
+/// The receive tasks are stopped and the ports released when the flow is dropped.
+/// Media from the client only goes on to the DP while its RTSP session is playing or recording.
+/// RTCP reports from the client keep its session alive.
+#[derive(Debug)]
+pub struct DpFlow {
+    proxy: DpProxy,
+    rtp_port: u16,
+    client_tx: mpsc::Sender<(FrameKind, Bytes)>,
+    metrics: Metrics,
+    // shared with the UDP relay tasks
+    client_media: Arc<AtomicBool>,
+    keepalive: Arc<Keepalive>,
+    // keyed by both the RTP and the RTCP channel of each track
+    tracks: Mutex<HashMap<u8, Arc<DpTrack>>>,
+    // keyed by the RTP channel of each track
+    tasks: Mutex<HashMap<u8, Vec<JoinHandle<()>>>>,
+}
+
+impl DpFlow {
+    /// first local RTP port of the flow (channel 0)
+    pub fn rtp_port(&self) -> u16 {
+        self.rtp_port
+    }
+
+    fn track(&self, channel: u8) -> Option<Arc<DpTrack>> {
+        lock(&self.tracks).get(&channel).cloned()
+    }
 
-    match UdpSocket::bind("0.0.0.0:".to_owned() + &rtp_port.to_string()).await {
+    /// lowest even channel that is free along with the next one up
+    fn free_channels(&self) -> Option<(u8, u8)> {
+        let tracks = lock(&self.tracks);
+        (0..DP_CHANNELS_PER_FLOW as u8 - 1).step_by(2)
+            .find(|channel| !tracks.contains_key(channel) && !tracks.contains_key(&(channel + 1)))
+            .map(|channel| (channel, channel + 1))
+    }
+}
+
+impl Drop for DpFlow {
+    fn drop(&mut self) {
+        for task in get_mut(&mut self.tasks).values().flatten() {
+            task.abort();
+        }
+    }
+}
+
+/// record the DP proxy addresses sent by the CP
+pub async fn dp_init(stub: &Stub, proxy: DpProxy) -> Result <()> {
+
+    trace!("DP proxy is {}", proxy);
+
+    let mut guard = write(&stub.dp.proxy);
+
+    // CP sends CONFIG again after every reconnect
+    match *guard {
+        Some(current) if current == proxy => debug!("DP already configured for {}", proxy),
+        Some(current) => warn!("DP moved from {} to {}, existing flows keep the old address", current, proxy),
+        None => debug!("DP configured for {}", proxy),
+    }
+
+    *guard = Some(proxy);
+    return Ok(())
+}
+
+/// Whether the CP has told us where the DP proxy is, so flows can get their sockets
+pub(crate) fn dp_ready(stub: &Stub) -> bool {
+    return read(&stub.dp.proxy).is_some()
+}
+
+/// bind a local UDP port and connect it to the DP proxy
+async fn dp_connect(local_port: u16, proxy: SocketAddr) -> Result<UdpSocket> {
+    match UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), local_port)).await {
         Ok(socket) => {
-            trace!("bound RTP listen socket");
-            match socket.connect(proxy_rtp).await {
-                Ok(()) => {
-                    trace!("connected to proxy");
-                    match RTP_TX.set(socket) {
-                        Ok(()) => trace!("connected RTP DP socket"), 
-                        _ => return Err(Error::new(ErrorKind::AlreadyExists, "RTP OnceCell already set")),
-                    }                    
-                },
-                Err(e) => return Err(e.into()),
+            match socket.connect(proxy).await {
+                Ok(()) => return Ok(socket),
+                Err(e) => return Err(e),
             }
         },
-        Err(e) => return Err(e.into()),
+        Err(e) => return Err(e),
     }
+}
 
-    let proxy_rtcp = SocketAddr::new(proxy_rtp.ip(), proxy_rtp.port()+1);
+/// bind the sockets for a channel pair at the given offset into the flow's port block
+async fn dp_track(proxy: DpProxy, rtp_port: u16, rtp_channel: u8, rtcp_channel: u8) -> Result<DpTrack> {
+    let offset = rtp_channel as u16;
+    if offset + 1 >= DP_CHANNELS_PER_FLOW {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("interleaved channel {} out of range", rtp_channel)))
+    }
 
-    trace!("RTCP proxy is {}", proxy_rtcp);
+    let (local_rtp, proxy_rtp, proxy_rtcp) = match (rtp_port.checked_add(offset + 1), proxy.rtp.port().checked_add(offset), proxy.rtcp.port().checked_add(offset)) {
+        (Some(_), Some(proxy_rtp), Some(proxy_rtcp)) => (rtp_port + offset, proxy_rtp, proxy_rtcp),
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "RTP port out of range")),
+    };
 
-    match UdpSocket::bind("0.0.0.0:".to_owned() + &rtcp_port.to_string()).await {
-        Ok(socket) => {
-            trace!("bound RTCP listen socket");
-            match socket.connect(proxy_rtcp).await {
-                Ok(()) => {
-                    match RTCP_TX.set(socket) {
-                        Ok(()) => {
-                            trace!("connected RTCP DP socket");
-                            return Ok(())
-                        },
-                        _ => return Err(Error::new(ErrorKind::AlreadyExists, "RTCP OnceCell already set")),
-                    }
+    match dp_connect(local_rtp, SocketAddr::new(proxy.rtp.ip(), proxy_rtp)).await {
+        Ok(rtp) => {
+            match dp_connect(local_rtp + 1, SocketAddr::new(proxy.rtcp.ip(), proxy_rtcp)).await {
+                Ok(rtcp) => {
+                    debug!("channels {}/{} using local RTP port {}, RTCP port {}", rtp_channel, rtcp_channel, local_rtp, local_rtp + 1);
+                    return Ok(DpTrack { rtp, rtcp, rtp_channel, rtcp_channel, client: None })
                 },
-                Err(e) => return Err(e.into()),
+                Err(e) => return Err(e),
             }
         },
-        Err(e) => return Err(e.into()),
-    }
-}
-
-/// demux interleaved data
-#[async_recursion]
-pub async fn dp_demux(length: usize, data: &mut [u8]) -> Result <(bool, usize, &mut [u8])> {
-    if length < 4 {
-        return Err(Error::new(ErrorKind::InvalidData, "Interleaved data too short"))
-    }
-    
-    let channel: usize = data[1].into();
-    let length_inside: usize = ((data[2] as u16) << 8 | data[3] as u16).into();
-    
-    trace!("Channel is {}", channel);
-    trace!("Length is {}", length);
-    trace!("Length inside is {}", length_inside);
-
-    if length < length_inside + 4 {
-        debug!("Remaining buffer is {} bytes, length inside is {} bytes", length, length_inside);
-        return Ok((true, 0, data))
-    }
-
-    // Send first (or only) RTP/RTCP data block 
-    match dp_send(data[4..length_inside+4].to_vec(), channel).await {
-        Ok(written) => {
-            trace!("wrote {} bytes to DP", written);
-            let next = written + 4;
-            let left = length - next;
-            if left > 0 {
-                trace!("recursing...");
-                // recursive call to demux will handle any remaining RTP/RTCP data blocks
-                match dp_demux(left, &mut data[next..]).await {
-                    Ok((fragment, wrote, offset)) => return Ok((fragment, wrote+written, offset)),
-                    Err(e) => return Err(Error::new(ErrorKind::Other, e.to_string())),  
+        Err(e) => return Err(e),
+    }
+}
+
+/// map a track into the flow and start relaying its media
+fn dp_add_track(flow: &DpFlow, track: DpTrack) {
+    let track = Arc::new(track);
+    let mut tasks = Vec::new();
+
+    if track.client.is_some() {
+        // RTP over UDP: relay datagrams between the client's ports and the DP in both directions
+        for (to_client, rtcp) in [(true, false), (true, true), (false, false), (false, true)] {
+            let relay_track = track.clone();
+            let relay_metrics = flow.metrics.clone();
+            let relay_media = flow.client_media.clone();
+            let relay_keepalive = flow.keepalive.clone();
+            tasks.push(tokio::spawn(async move {
+                match dp_client_relay(&relay_track, &relay_metrics, &relay_media, &relay_keepalive, to_client, rtcp).await {
+                    Ok(relayed) => info!("{} bytes relayed", relayed),
+                    Err(e) => debug!("UDP relay error {}", e),
                 }
+            }.in_current_span()));
+        }
+    } else {
+        let rtp_track = track.clone();
+        let rtcp_track = track.clone();
+        let rtp_tx = flow.client_tx.clone();
+        let rtcp_tx = flow.client_tx.clone();
+        let rtp_metrics = flow.metrics.clone();
+        let rtcp_metrics = flow.metrics.clone();
+
+        tasks.push(tokio::spawn(async move {
+            trace!("spawning thread for RTP receive");
+            match dp_rtp_recv(&rtp_track, &rtp_metrics, rtp_tx).await {
+                Ok(written) => info!("{} RTP bytes read", written),
+                Err(e) => debug!("RTP read error {}", e),
             }
-            else {
-                // All AOK
-                return Ok((false, written, data))
-            }     
+        }.in_current_span()));
+
+        tasks.push(tokio::spawn(async move {
+            trace!("spawning thread for RTCP receive");
+            match dp_rtcp_recv(&rtcp_track, &rtcp_metrics, rtcp_tx).await {
+                Ok(written) => info!("{} RTCP bytes read", written),
+                Err(e) => debug!("RTCP read error {}", e),
+            }
+        }.in_current_span()));
+    }
+
+    lock(&flow.tasks).insert(track.rtp_channel, tasks);
+
+    let mut tracks = lock(&flow.tracks);
+    tracks.insert(track.rtp_channel, track.clone());
+    tracks.insert(track.rtcp_channel, track);
+}
+
+/// allocate a block of local ports for a new flow, with a track for channels 0 and 1
+pub async fn dp_flow(stub: &Stub, client_tx: mpsc::Sender<(FrameKind, Bytes)>, keepalive: Arc<Keepalive>) -> Result<DpFlow> {
+    let proxy = *read(&stub.dp.proxy);
+
+    let proxy = match proxy {
+        Some(proxy) => proxy,
+        None => return Err(Error::new(ErrorKind::NotConnected, "DP not configured by CP")),
+    };
+
+    for block in 0..DP_MAX_FLOWS {
+        let rtp_port = match block.checked_mul(DP_CHANNELS_PER_FLOW).and_then(|offset| stub.dp.rtp_port.checked_add(offset)) {
+            Some(rtp_port) => rtp_port,
+            None => break,
+        };
+
+        match dp_track(proxy, rtp_port, DP_BASE_CHANNEL, DP_BASE_CHANNEL + 1).await {
+            Ok(track) => {
+                debug!("flow using local ports from {}", rtp_port);
+                let flow = DpFlow {
+                    proxy,
+                    rtp_port,
+                    client_tx,
+                    metrics: stub.metrics.clone(),
+                    client_media: Arc::new(AtomicBool::new(false)),
+                    keepalive: keepalive.clone(),
+                    tracks: Mutex::new(HashMap::new()),
+                    tasks: Mutex::new(HashMap::new()),
+                };
+                dp_add_track(&flow, track);
+                return Ok(flow)
+            },
+            Err(ref e) if e.kind() == ErrorKind::AddrInUse => continue,
+            Err(ref e) if e.kind() == ErrorKind::InvalidInput => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    return Err(Error::new(ErrorKind::AddrNotAvailable, "no free block of local RTP/RTCP ports"))
+}
+
+/// map an interleaved channel pair negotiated in SETUP to its own DP endpoint
+/// returns whether the pair was newly mapped, rather than already mapped the same way
+pub async fn dp_channels(flow: &DpFlow, rtp_channel: u8, rtcp_channel: u8) -> Result<bool> {
+    if let Some(track) = flow.track(rtp_channel) {
+        if track.rtp_channel == rtp_channel && track.rtcp_channel == rtcp_channel {
+            trace!("channels {}/{} already mapped", rtp_channel, rtcp_channel);
+            return Ok(false)
+        }
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("channel {} already mapped", rtp_channel)))
+    }
+
+    if flow.track(rtcp_channel).is_some() {
+        return Err(Error::new(ErrorKind::AlreadyExists, format!("channel {} already mapped", rtcp_channel)))
+    }
+
+    match dp_track(flow.proxy, flow.rtp_port, rtp_channel, rtcp_channel).await {
+        Ok(track) => {
+            dp_add_track(flow, track);
+            return Ok(true)
         },
         Err(e) => return Err(e),
     }
 }
 
+/// bind an even/odd pair of client-facing ports and connect them to the client's RTP/RTCP ports
+async fn dp_client_udp(client_rtp: SocketAddr, client_rtcp: SocketAddr) -> Result<DpClientUdp> {
+    for _ in 0..DP_CLIENT_PORT_ATTEMPTS {
+        let rtp = match UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)).await {
+            Ok(rtp) => rtp,
+            Err(e) => return Err(e),
+        };
+
+        let rtp_port = match rtp.local_addr() {
+            Ok(address) => address.port(),
+            Err(e) => return Err(e),
+        };
+
+        // RTCP goes on the next port up, so RTP needs an even port
+        if rtp_port % 2 != 0 || rtp_port == u16::MAX {
+            continue
+        }
+
+        let rtcp = match UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), rtp_port + 1)).await {
+            Ok(rtcp) => rtcp,
+            Err(ref e) if e.kind() == ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        };
+
+        match rtp.connect(client_rtp).await {
+            Ok(()) => trace!("client RTP socket connected to {}", client_rtp),
+            Err(e) => return Err(e),
+        }
+
+        match rtcp.connect(client_rtcp).await {
+            Ok(()) => trace!("client RTCP socket connected to {}", client_rtcp),
+            Err(e) => return Err(e),
+        }
+
+        return Ok(DpClientUdp { rtp, rtcp, rtp_port })
+    }
+
+    return Err(Error::new(ErrorKind::AddrNotAvailable, "no free client-facing RTP/RTCP port pair"))
+}
+
+/// add a track for a client that asked for RTP over UDP
+/// returns the channels the track is known by towards the CP and the local client-facing RTP port
+pub async fn dp_channels_udp(flow: &DpFlow, client_rtp: SocketAddr, client_rtcp: SocketAddr) -> Result<(u8, u8, u16)> {
+    let (rtp_channel, rtcp_channel) = match flow.free_channels() {
+        Some(channels) => channels,
+        None => return Err(Error::new(ErrorKind::AddrNotAvailable, "no free channels for UDP track")),
+    };
+
+    let client = match dp_client_udp(client_rtp, client_rtcp).await {
+        Ok(client) => client,
+        Err(e) => return Err(e),
+    };
+
+    match dp_track(flow.proxy, flow.rtp_port, rtp_channel, rtcp_channel).await {
+        Ok(mut track) => {
+            let server_port = client.rtp_port;
+            debug!("UDP track for client {} on channels {}/{}, local port {}", client_rtp, rtp_channel, rtcp_channel, server_port);
+            track.client = Some(client);
+            dp_add_track(flow, track);
+            return Ok((rtp_channel, rtcp_channel, server_port))
+        },
+        Err(e) => return Err(e),
+    }
+}
+
+/// remove a track (e.g. when SETUP fails), stopping its tasks and releasing its ports
+/// the base track stays, so another flow can't take the block while this one is still open
+pub fn dp_remove_channels(flow: &DpFlow, rtp_channel: u8) {
+    if rtp_channel == DP_BASE_CHANNEL {
+        trace!("keeping base track for channel {}", rtp_channel);
+        return
+    }
+
+    {
+        let mut tracks = lock(&flow.tracks);
+        if let Some(track) = tracks.remove(&rtp_channel) {
+            tracks.remove(&track.rtcp_channel);
+        }
+    }
+
+    let tasks = lock(&flow.tasks).remove(&rtp_channel);
+
+    for task in tasks.iter().flatten() {
+        task.abort();
+    }
+    debug!("removed track for channel {}", rtp_channel);
+}
+
+/// remove every track but the base one (e.g. on TEARDOWN), a later SETUP maps its channels afresh
+pub fn dp_remove_tracks(flow: &DpFlow) {
+    let mut channels: Vec<u8> = lock(&flow.tasks).keys().cloned().collect();
+    channels.sort_unstable();
+    for channel in channels {
+        dp_remove_channels(flow, channel);
+    }
+}
+
+/// let media from the client through to the DP, or stop it
+pub fn dp_allow_client_media(flow: &DpFlow, allowed: bool) {
+    debug!("client media to DP {}", if allowed { "allowed" } else { "stopped" });
+    flow.client_media.store(allowed, Ordering::SeqCst);
+}
+
+/// whether media from the client may go on to the DP, counting it as rejected if not
+fn dp_client_media_allowed(client_media: &AtomicBool, metrics: &Metrics, media: &str) -> bool {
+    if client_media.load(Ordering::SeqCst) {
+        return true
+    }
+    metrics.rejected_media.with_label_values(&[media]).inc();
+    return false
+}
+
+/// relay datagrams one way between the client's UDP port and the DP
+/// datagrams from the client are dropped unless the flow lets client media through, though its RTCP reports still keep it alive
+async fn dp_client_relay(track: &DpTrack, metrics: &Metrics, client_media: &AtomicBool, keepalive: &Keepalive, to_client: bool, rtcp: bool) -> Result<usize> {
+    let client = match &track.client {
+        Some(client) => client,
+        None => return Err(Error::new(ErrorKind::NotFound, "track has no client UDP sockets")),
+    };
+
+    let (from, to) = match (to_client, rtcp) {
+        (true, false) => (&track.rtp, &client.rtp),
+        (true, true) => (&track.rtcp, &client.rtcp),
+        (false, false) => (&client.rtp, &track.rtp),
+        (false, true) => (&client.rtcp, &track.rtcp),
+    };
+
+    let media = if rtcp { MEDIA_RTCP } else { MEDIA_RTP };
+    let direction = if to_client { TO_CLIENT } else { TO_DP };
+
+    let mut len = 0;
+    let mut buf = vec![0u8; 65536];
+    loop {
+        match from.recv(&mut buf).await {
+            Ok(rcvd) => {
+                len += rcvd;
+                if !to_client && rtcp && rtcp_is_report(&buf[..rcvd]) {
+                    keepalive.touch();
+                }
+                if !to_client && !dp_client_media_allowed(client_media, metrics, media) {
+                    trace!("dropped {} bytes from client outside PLAY or RECORD (RTCP {})", rcvd, rtcp);
+                    continue
+                }
+                match to.send(&buf[..rcvd]).await {
+                    Ok(sent) => {
+                        trace!("relayed {} bytes (to client {}, RTCP {})", sent, to_client, rtcp);
+                        metrics.media(media, direction, sent);
+                    },
+                    Err(e) => debug!("unable to relay UDP: {}", e),
+                }
+            },
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue, // try again
+            // ICMP port unreachable from a previous send shows up on the next receive
+            Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => continue,
+            Err(ref e) if e.kind() == ErrorKind::TimedOut => break,
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(len)
+}
+
 /// Send RTP/RTCP UDP packet to the DP
-pub async fn dp_send(data:Vec<u8>, channel: usize) -> Result <usize> {
-    if channel == 0 {
-        match RTP_TX.get() {
-            Some(socket) => {
-                loop {
-                    match socket.writable().await {
-                        Ok(()) => {
-    
-                            trace!("sending RTP data to DP");
-                    
-                            match socket.try_send(&data) {
-                                Ok(written) => {
-                                    trace!("{} RTP bytes written", written);
-                                    return Ok(written)
-                                },
-                                Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue,
-                                Err(e) => {
-                                    trace!("unable to send UDP");
-                                    return Err(e)
-                                }
-                            }
+/// fails with PermissionDenied if the client's session isn't playing or recording
+pub async fn dp_send(flow: &DpFlow, data: &[u8], channel: u8) -> Result <usize> {
+    let track = match flow.track(channel) {
+        Some(track) => track,
+        None => return Err(Error::new(ErrorKind::NotFound, format!("no DP track for channel {}", channel))),
+    };
+
+    if channel == track.rtcp_channel && rtcp_is_report(data) {
+        flow.keepalive.touch();
+    }
+
+    if !dp_client_media_allowed(&flow.client_media, &flow.metrics, if channel == track.rtp_channel { MEDIA_RTP } else { MEDIA_RTCP }) {
+        return Err(Error::new(ErrorKind::PermissionDenied, "client media before PLAY or RECORD"))
+    }
+
+    if channel == track.rtp_channel {
+        loop {
+            match track.rtp.writable().await {
+                Ok(()) => {
+
+                    trace!("sending RTP data to DP");
+
+                    match track.rtp.try_send(data) {
+                        Ok(written) => {
+                            trace!("{} RTP bytes written", written);
+                            flow.metrics.media(MEDIA_RTP, TO_DP, written);
+                            return Ok(written)
                         },
-                        Err(e) => return Err(e.into()),
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue,
+                        Err(e) => {
+                            trace!("unable to send UDP");
+                            return Err(e)
+                        }
                     }
-                }
-            },
-            None => return Err(Error::new(ErrorKind::NotFound, "RTP OnceCell not initlialised before write")),
+                },
+                Err(e) => return Err(e),
+            }
         }
     }
     else {
-        match RTCP_TX.get() {
-            Some(socket) => {
-                loop {
-                    match socket.writable().await {
-                        Ok(()) => {
-    
-                            trace!("sending RTCP data to DP");
-                    
-                            match socket.try_send(&data) {
-                                Ok(written) => return Ok(written),
-                                Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue,
-                                Err(e) => return Err(e),
-                            }
+        loop {
+            match track.rtcp.writable().await {
+                Ok(()) => {
+
+                    trace!("sending RTCP data to DP");
+
+                    match track.rtcp.try_send(data) {
+                        Ok(written) => {
+                            flow.metrics.media(MEDIA_RTCP, TO_DP, written);
+                            return Ok(written)
                         },
-                        Err(e) => return Err(e.into()),
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue,
+                        Err(e) => return Err(e),
                     }
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// receive RTP from the DP for an interleaved client
+/// frames are dropped rather than waited on if the client's writer is behind, so the socket keeps being read
+pub async fn dp_rtp_recv(track: &DpTrack, metrics: &Metrics, tx: mpsc::Sender::<(FrameKind, Bytes)>) -> Result<usize> {
+    let mut pool = FramePool::new();
+    let mut len = 0;
+    loop {
+        trace!("attempting receive from RTP socket");
+        match track.rtp.recv_buf(pool.recv_buf(track.rtp_channel)).await {
+            Ok (rcvd) => {
+                trace!("{} bytes of RTP data received", rcvd);
+                len += rcvd;
+                match tx.try_send((FrameKind::Rtp, pool.frame())) {
+                    Ok(()) => {
+                        debug!("sent RTP data to client");
+                        metrics.media(MEDIA_RTP, TO_CLIENT, rcvd);
+                    },
+                    Err(TrySendError::Full(_)) => {
+                        trace!("client writer behind, RTP dropped");
+                        metrics.client_dropped_frames.with_label_values(&[MEDIA_RTP]).inc();
+                    },
+                    Err(e) => warn!("unable to send RTP data, error{}",  e),
                 }
             },
-            None => return Err(Error::new(ErrorKind::NotFound, "RTCP OnceCell not initlialised before write")),
-        }
-    }
-}
-
-pub async fn dp_rtp_recv(tx: mpsc::Sender::<Vec<u8>>) -> Result<usize> {
-    match RTP_TX.get() {
-        Some(socket) => {
-            let mut len = 0;
-            loop {
-                let mut buf = [0u8; 65536];
-                trace!("attempting receive from RTP socket");
-                match socket.recv(&mut buf[4..]).await {
-                    Ok (rcvd) => {
-                        trace!("{} bytes of RTP data received", rcvd);
-                        len += rcvd;
-                        buf[0] = 0x24;
-                        buf[1] = 0;
-                        buf[2] = (rcvd as u16 >> 8) as u8;
-                        buf[3] = rcvd as u8;
-                        match tx.send((&buf[0..rcvd+4]).to_vec()).await {
-                            Ok(()) => debug!("sent RTP data to client"),
-                            Err(e) => warn!("unable to send RTP data, error{}",  e.to_string()),
-                        }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue, // try again
+            Err(ref e) if e.kind() == ErrorKind::TimedOut => break,
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(len)
+}
+
+/// receive RTCP from the DP for an interleaved client, dropping it rather than waiting if the client's writer is behind
+pub async fn dp_rtcp_recv(track: &DpTrack, metrics: &Metrics, tx: mpsc::Sender::<(FrameKind, Bytes)>) -> Result<usize> {
+    let mut pool = FramePool::new();
+    let mut len = 0;
+    loop {
+        trace!("attempting receive from RTCP socket");
+        match track.rtcp.recv_buf(pool.recv_buf(track.rtcp_channel)).await {
+            Ok (rcvd) => {
+                trace!("{} bytes of RTCP data received", rcvd);
+                len += rcvd;
+                match tx.try_send((FrameKind::Rtcp, pool.frame())) {
+                    Ok(()) => {
+                        debug!("sent RTCP data to client");
+                        metrics.media(MEDIA_RTCP, TO_CLIENT, rcvd);
                     },
-                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue, // try again
-                    Err(ref e) if e.kind() == ErrorKind::TimedOut => break,
-                    Err(e) => return Err(e),
-                }
-            }
-            return Ok(len)
-        },
-        None => return Err(Error::new(ErrorKind::NotFound, "RTP OnceCell not initlialised before read")),
-    }
-}
-
-pub async fn dp_rtcp_recv(tx: mpsc::Sender::<Vec<u8>>) -> Result<usize> {
-    match RTCP_TX.get() {
-        Some(socket) => {
-            let mut len = 0;
-            loop {
-                let mut buf = [0u8; 65536];
-                trace!("attempting receive from RTCP socket");
-                match socket.recv(&mut buf[4..]).await {
-                    Ok (rcvd) => {
-                        trace!("{} bytes of RTCP data received", rcvd);
-                        len += rcvd;
-                        buf[0] = 0x24;
-                        buf[1] = 1;
-                        buf[2] = (rcvd as u16 >> 8) as u8;
-                        buf[3] = rcvd as u8;
-                        match tx.send((&buf[0..rcvd+4]).to_vec()).await {
-                            Ok(()) => debug!("sent RTCP data to client"),
-                            Err(e) => warn!("unable to send RTCP data, error{}",  e.to_string()),
-                        }
+                    Err(TrySendError::Full(_)) => {
+                        trace!("client writer behind, RTCP dropped");
+                        metrics.client_dropped_frames.with_label_values(&[MEDIA_RTCP]).inc();
                     },
-                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue, // try again
-                    Err(ref e) if e.kind() == ErrorKind::TimedOut => break,
-                    Err(e) => return Err(e),
+                    Err(e) => warn!("unable to send RTCP data, error{}",  e),
                 }
-            }
-            return Ok(len)
-        },
-        None => return Err(Error::new(ErrorKind::NotFound, "RTCP OnceCell not initlialised before read")),
+            },
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue, // try again
+            Err(ref e) if e.kind() == ErrorKind::TimedOut => break,
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    /// a stub configured with a DP proxy, its local ports starting from a port that was just free
+    async fn dp_stub() -> Arc<Stub> {
+        let port = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).and_then(|socket| socket.local_addr()).map(|address| address.port()).expect("no free port");
+        let stub = Stub::new(port & !1);
+        dp_init(&stub, DpProxy::from_rtp(SocketAddr::from((Ipv4Addr::LOCALHOST, 9)))).await.expect("DP not configured");
+        return stub
+    }
+
+    async fn flow(stub: &Stub) -> DpFlow {
+        let (tx, _) = mpsc::channel(1);
+        return dp_flow(stub, tx, Arc::new(Keepalive::new())).await.expect("no DP flow")
+    }
+
+    #[tokio::test]
+    async fn teardown_keeps_the_port_block() {
+        let stub = dp_stub().await;
+        let first = flow(&stub).await;
+        assert!(dp_channels(&first, 2, 3).await.unwrap());
+
+        dp_remove_tracks(&first);
+        // aborted relay tasks let go of their sockets once the runtime gets to them
+        tokio::task::yield_now().await;
+        assert!(first.track(0).is_some() && first.track(1).is_some());
+        assert!(first.track(2).is_none() && first.track(3).is_none());
+
+        // a new flow can't take the block of one still open
+        let second = flow(&stub).await;
+        assert_ne!(second.rtp_port(), first.rtp_port());
+
+        // and the torn down flow can set up again
+        assert!(dp_channels(&first, 2, 3).await.unwrap());
+        assert!(!dp_channels(&first, 0, 1).await.unwrap());
+
+        // once it is dropped its block is free again
+        let rtp_port = first.rtp_port();
+        drop(first);
+        tokio::task::yield_now().await;
+        assert_eq!(flow(&stub).await.rtp_port(), rtp_port);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn channel_pairs_map_within_the_block() {
+        let stub = dp_stub().await;
+        let flow = flow(&stub).await;
+
+        // 7 and up are past the flow's block of ports
+        for (rtp_channel, rtcp_channel) in [(7, 8), (8, 9), (254, 255)] {
+            assert_eq!(dp_channels(&flow, rtp_channel, rtcp_channel).await.unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+
+        assert!(dp_channels(&flow, 2, 3).await.unwrap());
+        assert!(dp_channels(&flow, 6, 7).await.unwrap());
+        assert!(!dp_channels(&flow, 2, 3).await.unwrap());
+        assert_eq!(flow.track(7).map(|track| track.rtp_channel), Some(6));
+        assert_eq!(flow.free_channels(), Some((4, 5)));
+    }
+
+    #[tokio::test]
+    async fn mapped_channels_are_not_remapped() {
+        let stub = dp_stub().await;
+        let flow = flow(&stub).await;
+        assert!(dp_channels(&flow, 2, 3).await.unwrap());
+
+        for (rtp_channel, rtcp_channel) in [(3, 4), (4, 3), (2, 5), (1, 2)] {
+            assert_eq!(dp_channels(&flow, rtp_channel, rtcp_channel).await.unwrap_err().kind(), ErrorKind::AlreadyExists);
+        }
+        assert!(flow.track(4).is_none() && flow.track(5).is_none());
+    }
+
+    /// a flow whose keepalive the test holds on to
+    async fn flow_with_keepalive(stub: &Stub) -> (DpFlow, Arc<Keepalive>) {
+        let (tx, _) = mpsc::channel(1);
+        let keepalive = Arc::new(Keepalive::new());
+        return (dp_flow(stub, tx, keepalive.clone()).await.expect("no DP flow"), keepalive)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn interleaved_rtcp_reports_keep_the_flow_alive() {
+        let stub = dp_stub().await;
+        let (flow, keepalive) = flow_with_keepalive(&stub).await;
+
+        // media is held back before PLAY, which leaves the keepalive to the packet type
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(dp_send(&flow, &[0x80, 96, 0, 1], 0).await.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(dp_send(&flow, &[0x80, 204, 0, 1], 1).await.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(keepalive.idle(), Duration::from_secs(10));
+
+        // a receiver report counts even though it is dropped
+        assert_eq!(dp_send(&flow, &[0x80, 201, 0, 1], 1).await.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(keepalive.idle(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn udp_rtcp_reports_keep_the_flow_alive() {
+        let stub = dp_stub().await;
+        let (flow, keepalive) = flow_with_keepalive(&stub).await;
+
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.expect("no client socket");
+        let client_addr = client.local_addr().unwrap();
+        let (_, _, server_port) = dp_channels_udp(&flow, client_addr, client_addr).await.expect("no UDP track");
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        client.send_to(&[0x80, 200, 0, 1], (Ipv4Addr::LOCALHOST, server_port + 1)).await.expect("report not sent");
+
+        // the relay runs on real sockets, so wait in real time for it to see the sender report
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while keepalive.idle() >= Duration::from_secs(10) {
+            assert!(Instant::now() < deadline, "report not seen by relay");
+            tokio::task::yield_now().await;
+        }
+    }
+}
@@ -14,6 +14,16 @@
  * limitations under the License.
  */
 
+pub mod admin;
 pub mod client;
 pub mod cp;
+pub mod demux;
 pub mod dp;
+mod lock;
+pub mod media;
+pub mod metrics;
+pub mod pool;
+pub mod queue;
+pub mod rtsp;
+pub mod session;
+pub mod stub;
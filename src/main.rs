@@ -14,56 +14,335 @@
  * limitations under the License.
  */
 
+use msm_rtsp_stub::admin::admin_server;
 use msm_rtsp_stub::client::client_listener;
-use msm_rtsp_stub::cp::cp_connector;
+use msm_rtsp_stub::cp::{cp_close, cp_connector};
+use msm_rtsp_stub::stub::Stub;
 
-use futures::future::join_all;
+use clap::{Parser, ValueEnum};
 use http::Uri;
-use log::{info, error};
+use log::{info, error, warn};
+use std::io::Result;
+use std::net::{Ipv6Addr, SocketAddr};
 use std::str::FromStr;
+use std::time::Duration;
 
-use simple_logger;
-use envmnt;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
 
-#[tokio::main (flavor="current_thread")]
-async fn main() {
+use tokio::runtime::{Builder, Runtime};
+use tokio::time::{timeout_at, Instant};
+use tracing_subscriber::filter::{filter_fn, LevelFilter};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{Layer, Registry};
 
-    // We prefer to use MSM_LOG_LVL rather than RUST_LOG
-    envmnt::set("RUST_LOG", envmnt::get_or("MSM_LOG_LVL", "WARN"));
+/// service name on exported traces
+const OTLP_SERVICE_NAME: &str = "msm-rtsp-stub";
 
-    match simple_logger::init_with_env() {
-        Ok(()) => {
-            let rtsp_port = envmnt::get_u16("RTSP_PROXY_PORT", 8554);
-                    
-            match Uri::from_str(&envmnt::get_or("MSM_CONTROL_PLANE", "http://127.0.0.1:9000")) {
-                Ok(control_plane) => {
-                    let mut handles = vec![];
-                    // spawn a green thread for the client communication
-                    handles.push(tokio::spawn(async move {
-                        match client_listener(format!(":::{}", rtsp_port).to_string()).await {
-                            Ok(()) => info!("Disconnected!"),
-                            Err(e) => error!("Error: {}", e),
-                        }
-                    }));
-                
-                    // spawn a green thread for the CP communication
-                    handles.push(tokio::spawn(async move {
-                        match cp_connector(control_plane).await {
-                            Ok(()) => info!("Disconnected!"),
-                            Err(e) => error!("Error: {}", e),
-                        }
-                    }));
-                
-                    // wait for both green threads to finish (not gonna happen)
-                    join_all(handles).await;
+/// how log lines are written
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    /// human readable lines
+    Text,
+    /// one JSON object per line, for log pipelines
+    Json,
+}
+
+/// tokio runtime the stub runs on
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum RuntimeFlavor {
+    /// everything on one thread
+    CurrentThread,
+    /// clients, the CP stream and media spread over worker threads
+    MultiThread,
+}
+
+/// RTSP sidecar stub proxy
+///
+/// Every setting can also be given by its environment variable.
+#[derive(Parser, Debug)]
+#[clap(author, version, about)]
+struct Args {
+    /// log level (off, error, warn, info, debug or trace)
+    #[clap(long, env = "MSM_LOG_LVL", default_value = "warn", value_parser = clap::value_parser!(LevelFilter))]
+    log_level: LevelFilter,
+
+    /// log format, each line carries the client flow and RTSP request it belongs to
+    #[clap(long, env = "MSM_LOG_FORMAT", value_enum, default_value = "text")]
+    log_format: LogFormat,
+
+    /// port to listen on for RTSP clients
+    #[clap(long, env = "RTSP_PROXY_PORT", default_value_t = 8554, value_parser = clap::value_parser!(u16).range(1..))]
+    rtsp_port: u16,
+
+    /// gRPC URI of the control plane
+    #[clap(long, env = "MSM_CONTROL_PLANE", default_value = "http://127.0.0.1:9000", value_parser = parse_absolute_uri)]
+    control_plane: Uri,
+
+    /// gRPC URI of an OTLP collector to export request traces to, none are exported without it
+    #[clap(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT", value_parser = parse_absolute_uri)]
+    otlp_endpoint: Option<Uri>,
+
+    /// first local RTP port towards the DP (RTCP uses the next port up)
+    #[clap(long, env = "LOCAL_RTP_PORT", default_value_t = 8050, value_parser = clap::value_parser!(u16).range(1..65535))]
+    local_rtp_port: u16,
+
+    /// port for the admin HTTP server with the Prometheus /metrics endpoint
+    #[clap(long, env = "MSM_ADMIN_PORT", default_value_t = 9464, value_parser = clap::value_parser!(u16).range(1..))]
+    admin_port: u16,
+
+    /// seconds to wait for flows to close and the CP stream to finish on shutdown
+    #[clap(long, env = "MSM_SHUTDOWN_GRACE", default_value_t = 10)]
+    shutdown_grace: u64,
+
+    /// runtime to run on, multi-thread lets a stub with many clients use more than one core
+    #[clap(long, env = "MSM_RUNTIME", value_enum, default_value = "current-thread")]
+    runtime: RuntimeFlavor,
+
+    /// worker threads for the multi-thread runtime, one per core if not given
+    #[clap(long, env = "MSM_WORKER_THREADS", value_parser = clap::value_parser!(u16).range(1..))]
+    worker_threads: Option<u16>,
+}
+
+/// the CP and OTLP URIs must be absolute for the gRPC connection
+fn parse_absolute_uri(uri: &str) -> std::result::Result<Uri, String> {
+    match Uri::from_str(uri) {
+        Ok(parsed) if parsed.scheme().is_some() && parsed.authority().is_some() => return Ok(parsed),
+        Ok(_) => return Err("expected an absolute URI such as http://127.0.0.1:9000".to_string()),
+        Err(e) => return Err(e.to_string()),
+    }
+}
+
+/// OTLP exporter for the flow and request spans, batched on the given runtime
+fn init_tracer<R: trace::TraceRuntime>(endpoint: &Uri, runtime: R) -> std::result::Result<trace::Tracer, opentelemetry::trace::TraceError> {
+    return opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint.to_string()))
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![KeyValue::new("service.name", OTLP_SERVICE_NAME)])))
+        .install_batch(runtime)
+}
+
+/// log through tracing so lines carry the span context, including those from the log crate
+/// spans also go to the OTLP collector if there is one
+///
+/// The log level only filters the lines written. Flow and request spans are info level and always kept,
+/// so lines carry their context and traces are exported whatever the log level, even with logging off.
+fn init_logging(level: LevelFilter, format: LogFormat, otlp_endpoint: Option<&Uri>, flavor: RuntimeFlavor) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // spans at debug and below only when logging that much
+    let span_level = std::cmp::max(level, LevelFilter::INFO);
+    let fmt_filter = filter_fn(move |metadata| *metadata.level() <= if metadata.is_span() { span_level } else { level })
+        .with_max_level_hint(span_level);
+
+    let fmt_layer: Box<dyn Layer<Registry> + Send + Sync> = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().with_current_span(true).with_span_list(true).boxed(),
+    };
+
+    let otlp_layer = match otlp_endpoint {
+        Some(endpoint) => {
+            // the current-thread batch runtime exports from a thread of its own so the stub's thread isn't needed
+            let tracer = match flavor {
+                RuntimeFlavor::CurrentThread => init_tracer(endpoint, opentelemetry::runtime::TokioCurrentThread),
+                RuntimeFlavor::MultiThread => init_tracer(endpoint, opentelemetry::runtime::Tokio),
+            };
+            match tracer {
+                Ok(tracer) => Some(tracing_opentelemetry::layer().with_tracer(tracer).with_filter(span_level)),
+                Err(e) => return Err(Box::new(e)),
+            }
+        },
+        None => None,
+    };
+
+    return tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(fmt_filter))
+        .with(otlp_layer)
+        .try_init()
+        .map_err(|e| e.into())
+}
+
+/// wait for SIGTERM (from Kubernetes) or SIGINT
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            tokio::select! {
+                _ = sigterm.recv() => return Ok("SIGTERM"),
+                result = tokio::signal::ctrl_c() => {
+                    match result {
+                        Ok(()) => return Ok("SIGINT"),
+                        Err(e) => return Err(e),
+                    }
                 },
-                Err(e) => {
-                    error!("unable to parse control plane URI {}", e.to_string());
+            }
+        },
+        Err(e) => return Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<&'static str> {
+    match tokio::signal::ctrl_c().await {
+        Ok(()) => return Ok("ctrl-c"),
+        Err(e) => return Err(e),
+    }
+}
+
+/// runtime of the chosen flavor, with a worker per core by default for multi-thread
+fn build_runtime(flavor: RuntimeFlavor, worker_threads: Option<u16>) -> Result<Runtime> {
+    let mut builder = match flavor {
+        RuntimeFlavor::CurrentThread => Builder::new_current_thread(),
+        RuntimeFlavor::MultiThread => Builder::new_multi_thread(),
+    };
+    if let (RuntimeFlavor::MultiThread, Some(workers)) = (flavor, worker_threads) {
+        builder.worker_threads(workers as usize);
+    }
+    return builder.enable_all().build()
+}
+
+fn main() {
+
+    // exits with usage on bad or missing values
+    let args = Args::parse();
+
+    match build_runtime(args.runtime, args.worker_threads) {
+        Ok(runtime) => runtime.block_on(run(args)),
+        Err(e) => eprintln!("unable to start runtime: {}", e),
+    }
+}
+
+async fn run(args: Args) {
+    match init_logging(args.log_level, args.log_format, args.otlp_endpoint.as_ref(), args.runtime) {
+        Ok(()) => {
+            if args.runtime == RuntimeFlavor::CurrentThread && args.worker_threads.is_some() {
+                warn!("worker threads are only used by the multi-thread runtime");
+            }
+            info!("running on the {:?} runtime", args.runtime);
+
+            let rtsp_port = args.rtsp_port;
+            let control_plane = args.control_plane;
+            let grace_period = Duration::from_secs(args.shutdown_grace);
+
+            let stub = Stub::new(args.local_rtp_port);
+            let client_stub = stub.clone();
+            let cp_stub = stub.clone();
+            let admin_stub = stub.clone();
+            let admin_address = SocketAddr::from((Ipv6Addr::UNSPECIFIED, args.admin_port));
+
+            // spawn a green thread for the client communication
+            let client_handle = tokio::spawn(async move {
+                match client_listener(client_stub, format!(":::{}", rtsp_port)).await {
+                    Ok(()) => info!("Stopped listening"),
+                    Err(e) => error!("Error: {}", e),
+                }
+            });
+
+            // spawn a green thread for metrics and health checks, it runs until we exit
+            tokio::spawn(async move {
+                match admin_server(admin_stub, admin_address).await {
+                    Ok(()) => info!("Admin server stopped"),
+                    Err(e) => error!("Admin server error: {}", e),
+                }
+            });
+
+            // spawn a green thread for the CP communication
+            let cp_handle = tokio::spawn(async move {
+                match cp_connector(cp_stub, control_plane).await {
+                    Ok(()) => info!("Disconnected!"),
+                    Err(e) => error!("Error: {}", e),
                 }
+            });
+
+            // run until told to stop
+            match shutdown_signal().await {
+                Ok(signal) => info!("{} received, shutting down", signal),
+                Err(e) => error!("unable to wait for signals, shutting down: {}", e),
+            }
+
+            // stop accepting, then let every client handler close and send its DELETE
+            let deadline = Instant::now() + grace_period;
+            stub.shutdown();
+
+            match timeout_at(deadline, client_handle).await {
+                Ok(_) => info!("listener stopped"),
+                Err(_) => warn!("listener still running after grace period"),
+            }
+
+            match timeout_at(deadline, stub.drained()).await {
+                Ok(()) => info!("all flows closed"),
+                Err(_) => warn!("{} flows still open after grace period", stub.active_flows()),
+            }
+
+            // now close the gRPC stream once the DELETEs have gone out
+            cp_close(&stub);
+
+            match timeout_at(deadline, cp_handle).await {
+                Ok(_) => info!("CP stream closed"),
+                Err(_) => warn!("CP stream still open after grace period"),
             }
+
+            // flush any spans still waiting to be exported
+            opentelemetry::global::shutdown_tracer_provider();
         },
         Err(e) => {
-            error!("unable to log: {}", e.to_string());
+            eprintln!("unable to log: {}", e);
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::error::ErrorKind;
+    use clap::CommandFactory;
+    use std::sync::Mutex;
+
+    /// held while a test reads or changes the environment the args fall back to
+    static ENVIRONMENT: Mutex<()> = Mutex::new(());
+
+    fn parse_in_environment(args: &[&str]) -> std::result::Result<Args, clap::Error> {
+        return Args::try_parse_from(std::iter::once("msm_rtsp_stub").chain(args.iter().copied()))
+    }
+
+    fn parse(args: &[&str]) -> std::result::Result<Args, clap::Error> {
+        let _environment = ENVIRONMENT.lock().unwrap_or_else(|e| e.into_inner());
+        return parse_in_environment(args)
+    }
+
+    #[test]
+    fn args_are_consistent() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn out_of_range_ports_are_rejected() {
+        // RTCP needs the port above the first RTP port
+        assert_eq!(parse(&["--local-rtp-port", "65535"]).unwrap_err().kind(), ErrorKind::ValueValidation);
+        assert_eq!(parse(&["--local-rtp-port", "65534"]).unwrap().local_rtp_port, 65534);
+
+        assert_eq!(parse(&["--rtsp-port", "0"]).unwrap_err().kind(), ErrorKind::ValueValidation);
+        assert_eq!(parse(&["--admin-port", "0"]).unwrap_err().kind(), ErrorKind::ValueValidation);
+        assert_eq!(parse(&["--worker-threads", "0"]).unwrap_err().kind(), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn environment_is_a_fallback_for_flags() {
+        let _environment = ENVIRONMENT.lock().unwrap_or_else(|e| e.into_inner());
+
+        std::env::set_var("MSM_ADMIN_PORT", "9999");
+        std::env::set_var("MSM_LOG_FORMAT", "json");
+        let args = parse_in_environment(&[]).unwrap();
+        assert_eq!((args.admin_port, args.log_format), (9999, LogFormat::Json));
+        assert_eq!(parse_in_environment(&["--admin-port", "9000"]).unwrap().admin_port, 9000);
+
+        // and is checked like the flag
+        std::env::set_var("MSM_ADMIN_PORT", "0");
+        assert_eq!(parse_in_environment(&[]).unwrap_err().kind(), ErrorKind::ValueValidation);
+
+        std::env::remove_var("MSM_ADMIN_PORT");
+        std::env::remove_var("MSM_LOG_FORMAT");
+        assert_eq!(parse_in_environment(&[]).unwrap().admin_port, 9464);
+    }
+}
@@ -0,0 +1,304 @@
+/*
+ * Copyright (c) 2022 Cisco and/or its affiliates.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at:
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::str::FromStr;
+
+/// RTP fixed header size, before any CSRCs or header extension
+const RTP_HEADER_SIZE: usize = 12;
+const RTP_VERSION: u8 = 2;
+
+/// RTCP sender and receiver report packet types (RFC 3550), one of which starts every compound packet
+const RTCP_SR: u8 = 200;
+const RTCP_RR: u8 = 201;
+
+/// H.264 NAL unit types (RFC 6184)
+const H264_IDR: u8 = 5;
+const H264_SPS: u8 = 7;
+const H264_STAP_A: u8 = 24;
+const H264_FU_A: u8 = 28;
+
+/// H.265 NAL unit types (RFC 7798), IRAP pictures are types 16 to 21
+const H265_IRAP_FIRST: u8 = 16;
+const H265_IRAP_LAST: u8 = 21;
+const H265_VPS: u8 = 32;
+const H265_PPS: u8 = 34;
+const H265_AP: u8 = 48;
+const H265_FU: u8 = 49;
+
+/// start bit of a fragmentation unit header
+const FU_START: u8 = 0x80;
+
+/// Video codecs whose keyframes can be found in RTP payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    H264,
+    H265,
+}
+
+impl FromStr for Codec {
+    type Err = ();
+
+    /// codec from an SDP rtpmap encoding name
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_uppercase().as_str() {
+            "H264" => return Ok(Codec::H264),
+            "H265" | "HEVC" => return Ok(Codec::H265),
+            _ => return Err(()),
+        }
+    }
+}
+
+impl Codec {
+    /// whether an RTP payload starts a keyframe, or carries the parameter sets sent just before one
+    pub fn starts_keyframe(&self, payload: &[u8]) -> bool {
+        match self {
+            Codec::H264 => return h264_starts_keyframe(payload),
+            Codec::H265 => return h265_starts_keyframe(payload),
+        }
+    }
+}
+
+fn h264_keyframe_nal(nal_type: u8) -> bool {
+    return nal_type == H264_IDR || nal_type == H264_SPS
+}
+
+fn h264_starts_keyframe(payload: &[u8]) -> bool {
+    let nal_type = match payload.first() {
+        Some(header) => header & 0x1f,
+        None => return false,
+    };
+
+    match nal_type {
+        H264_STAP_A => return aggregated_nals(&payload[1..]).any(|nal| h264_keyframe_nal(nal[0] & 0x1f)),
+        H264_FU_A => {
+            match payload.get(1) {
+                Some(fu_header) => return fu_header & FU_START != 0 && h264_keyframe_nal(fu_header & 0x1f),
+                None => return false,
+            }
+        },
+        nal_type => return h264_keyframe_nal(nal_type),
+    }
+}
+
+fn h265_keyframe_nal(nal_type: u8) -> bool {
+    return (H265_IRAP_FIRST..=H265_IRAP_LAST).contains(&nal_type) || (H265_VPS..=H265_PPS).contains(&nal_type)
+}
+
+fn h265_starts_keyframe(payload: &[u8]) -> bool {
+    let nal_type = match payload.first() {
+        Some(header) if payload.len() >= 2 => (header >> 1) & 0x3f,
+        _ => return false,
+    };
+
+    match nal_type {
+        H265_AP => return aggregated_nals(&payload[2..]).any(|nal| nal.len() >= 2 && h265_keyframe_nal((nal[0] >> 1) & 0x3f)),
+        H265_FU => {
+            match payload.get(2) {
+                Some(fu_header) => return fu_header & FU_START != 0 && h265_keyframe_nal(fu_header & 0x3f),
+                None => return false,
+            }
+        },
+        nal_type => return h265_keyframe_nal(nal_type),
+    }
+}
+
+/// NAL units of an aggregation packet, each preceded by its 16-bit size
+fn aggregated_nals(mut data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        if data.len() < 2 {
+            return None
+        }
+        let size = u16::from_be_bytes([data[0], data[1]]) as usize;
+        if size == 0 || data.len() < 2 + size {
+            return None
+        }
+        let nal = &data[2..2 + size];
+        data = &data[2 + size..];
+        Some(nal)
+    })
+}
+
+/// payload type and payload of an RTP packet, skipping CSRCs, header extension and padding
+pub fn rtp_payload(packet: &[u8]) -> Option<(u8, &[u8])> {
+    if packet.len() < RTP_HEADER_SIZE || packet[0] >> 6 != RTP_VERSION {
+        return None
+    }
+
+    let payload_type = packet[1] & 0x7f;
+    let mut start = RTP_HEADER_SIZE + 4 * (packet[0] & 0x0f) as usize;
+    if packet[0] & 0x10 != 0 {
+        let length = packet.get(start + 2..start + 4)?;
+        start += 4 + 4 * u16::from_be_bytes([length[0], length[1]]) as usize;
+    }
+
+    let mut end = packet.len();
+    if packet[0] & 0x20 != 0 {
+        end = end.checked_sub(*packet.last()? as usize)?;
+    }
+
+    if start > end {
+        return None
+    }
+    return Some((payload_type, &packet[start..end]))
+}
+
+/// whether an RTCP packet is a sender or receiver report, as a live client sends periodically
+pub fn rtcp_is_report(packet: &[u8]) -> bool {
+    match (packet.first(), packet.get(1)) {
+        (Some(first), Some(packet_type)) => return first >> 6 == RTP_VERSION && (*packet_type == RTCP_SR || *packet_type == RTCP_RR),
+        _ => return false,
+    }
+}
+
+/// payload types of the video codecs in the a=rtpmap lines of an SDP body
+pub fn sdp_codecs(sdp: &[u8]) -> Vec<(u8, Codec)> {
+    String::from_utf8_lossy(sdp).lines()
+        .filter_map(|line| line.trim().strip_prefix("a=rtpmap:"))
+        .filter_map(|rtpmap| {
+            let (payload_type, encoding) = rtpmap.split_once(' ')?;
+            let name = encoding.trim().split('/').next()?;
+            match (u8::from_str(payload_type.trim()), Codec::from_str(name)) {
+                (Ok(payload_type), Ok(codec)) => Some((payload_type, codec)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RTP packet with the given first header byte, payload type and the bytes after the fixed header
+    fn rtp(first: u8, payload_type: u8, rest: &[u8]) -> Vec<u8> {
+        let mut packet = vec![first, 0x80 | payload_type, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        packet.extend_from_slice(rest);
+        return packet
+    }
+
+    #[test]
+    fn rtp_payload_of_a_plain_packet() {
+        let packet = rtp(0x80, 96, &[0x65, 1, 2]);
+        assert_eq!(rtp_payload(&packet), Some((96, &[0x65, 1, 2][..])));
+        assert_eq!(rtp_payload(&rtp(0x80, 96, &[])), Some((96, &[][..])));
+    }
+
+    #[test]
+    fn rtp_payload_skips_csrcs() {
+        let packet = rtp(0x82, 97, &[0, 0, 0, 4, 0, 0, 0, 5, 0x41, 9]);
+        assert_eq!(rtp_payload(&packet), Some((97, &[0x41, 9][..])));
+        assert_eq!(rtp_payload(&rtp(0x82, 97, &[0, 0, 0, 4])), None);
+    }
+
+    #[test]
+    fn rtp_payload_skips_header_extension() {
+        let packet = rtp(0x90, 96, &[0xbe, 0xde, 0, 1, 1, 2, 3, 4, 0x65]);
+        assert_eq!(rtp_payload(&packet), Some((96, &[0x65][..])));
+
+        // after the CSRCs
+        let packet = rtp(0x91, 96, &[0, 0, 0, 4, 0xbe, 0xde, 0, 0, 0x65]);
+        assert_eq!(rtp_payload(&packet), Some((96, &[0x65][..])));
+
+        assert_eq!(rtp_payload(&rtp(0x90, 96, &[0xbe, 0xde])), None);
+        assert_eq!(rtp_payload(&rtp(0x90, 96, &[0xbe, 0xde, 0, 2, 1, 2, 3, 4])), None);
+    }
+
+    #[test]
+    fn rtp_payload_strips_padding() {
+        let packet = rtp(0xa0, 96, &[0x65, 1, 0, 0, 3]);
+        assert_eq!(rtp_payload(&packet), Some((96, &[0x65, 1][..])));
+        assert_eq!(rtp_payload(&rtp(0xa0, 96, &[0, 9])), None);
+    }
+
+    #[test]
+    fn rtp_payload_rejects_short_or_other_versions() {
+        assert_eq!(rtp_payload(&[0x80, 96, 0, 1]), None);
+        assert_eq!(rtp_payload(&rtp(0x40, 96, &[0x65])), None);
+    }
+
+    #[test]
+    fn h264_single_nal_keyframes() {
+        assert!(h264_starts_keyframe(&[0x65, 0x88]));
+        assert!(h264_starts_keyframe(&[0x67, 0x42]));
+        assert!(!h264_starts_keyframe(&[0x41, 0x9a]));
+        assert!(!h264_starts_keyframe(&[0x68, 0xce]));
+        assert!(!h264_starts_keyframe(&[]));
+    }
+
+    #[test]
+    fn h264_stap_a_keyframes() {
+        // SPS and PPS aggregated ahead of an IDR
+        assert!(h264_starts_keyframe(&[0x78, 0, 2, 0x67, 0x42, 0, 2, 0x68, 0xce]));
+        assert!(!h264_starts_keyframe(&[0x78, 0, 2, 0x41, 0x9a, 0, 1, 0x06]));
+        // a NAL unit overrunning the packet ends the aggregation
+        assert!(!h264_starts_keyframe(&[0x78, 0, 2, 0x41, 0x9a, 0, 9, 0x67]));
+        assert!(!h264_starts_keyframe(&[0x78]));
+    }
+
+    #[test]
+    fn h264_fu_a_keyframes() {
+        assert!(h264_starts_keyframe(&[0x7c, 0x85, 0x88]));
+        // only the first fragment starts the keyframe
+        assert!(!h264_starts_keyframe(&[0x7c, 0x05, 0x88]));
+        assert!(!h264_starts_keyframe(&[0x7c, 0x81, 0x9a]));
+        assert!(!h264_starts_keyframe(&[0x7c]));
+    }
+
+    #[test]
+    fn h265_single_nal_keyframes() {
+        assert!(h265_starts_keyframe(&[0x26, 0x01, 0xaf]));
+        assert!(h265_starts_keyframe(&[0x2a, 0x01]));
+        assert!(h265_starts_keyframe(&[0x40, 0x01, 0x0c]));
+        assert!(h265_starts_keyframe(&[0x44, 0x01]));
+        assert!(!h265_starts_keyframe(&[0x02, 0x01, 0xd0]));
+        assert!(!h265_starts_keyframe(&[0x4e, 0x01]));
+        assert!(!h265_starts_keyframe(&[0x26]));
+    }
+
+    #[test]
+    fn h265_ap_keyframes() {
+        // VPS aggregated ahead of an IRAP
+        assert!(h265_starts_keyframe(&[0x60, 0x01, 0, 3, 0x40, 0x01, 0x0c, 0, 2, 0x42, 0x01]));
+        assert!(!h265_starts_keyframe(&[0x60, 0x01, 0, 3, 0x02, 0x01, 0xd0, 0, 2, 0x4e, 0x01]));
+        // NAL units too short for a header are skipped
+        assert!(!h265_starts_keyframe(&[0x60, 0x01, 0, 1, 0x40]));
+        assert!(!h265_starts_keyframe(&[0x60, 0x01]));
+    }
+
+    #[test]
+    fn h265_fu_keyframes() {
+        assert!(h265_starts_keyframe(&[0x62, 0x01, 0x93, 0xaf]));
+        assert!(!h265_starts_keyframe(&[0x62, 0x01, 0x13, 0xaf]));
+        assert!(!h265_starts_keyframe(&[0x62, 0x01, 0x81, 0xd0]));
+        assert!(!h265_starts_keyframe(&[0x62, 0x01]));
+    }
+
+    #[test]
+    fn codecs_from_sdp() {
+        let sdp = b"v=0\r\nm=video 0 RTP/AVP 96 98\r\na=rtpmap:96 H264/90000\r\na=rtpmap:98 H265/90000\r\nm=audio 0 RTP/AVP 97\r\na=rtpmap:97 MPEG4-GENERIC/48000/2\r\n";
+        assert_eq!(sdp_codecs(sdp), vec![(96, Codec::H264), (98, Codec::H265)]);
+        assert_eq!(Codec::from_str("hevc"), Ok(Codec::H265));
+    }
+
+    #[test]
+    fn rtcp_reports() {
+        assert!(rtcp_is_report(&[0x80, RTCP_SR, 0, 6]));
+        assert!(rtcp_is_report(&[0x81, RTCP_RR, 0, 7]));
+        assert!(!rtcp_is_report(&[0x81, 203, 0, 1]));
+        assert!(!rtcp_is_report(&[0x00, RTCP_RR, 0, 1]));
+        assert!(!rtcp_is_report(&[0x80]));
+    }
+}
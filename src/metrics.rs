@@ -0,0 +1,143 @@
+/*
+ * Copyright (c) 2022 Cisco and/or its affiliates.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at:
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::rtsp::RtspMethod;
+
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+
+use std::fmt;
+use std::io::{Error, Result};
+
+const METRICS_NAMESPACE: &str = "msm_rtsp_stub";
+
+/// media label values
+pub(crate) const MEDIA_RTP: &str = "rtp";
+pub(crate) const MEDIA_RTCP: &str = "rtcp";
+
+/// direction label values
+pub(crate) const TO_DP: &str = "to_dp";
+pub(crate) const TO_CLIENT: &str = "to_client";
+
+/// Counters and gauges for one stub, exported in the Prometheus text format
+///
+/// Cloning is cheap and every clone updates the same metrics, so flows and their tasks keep their own copy.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub(crate) active_flows: IntGauge,
+    pub(crate) accepted_connections: IntCounter,
+    pub(crate) rtsp_requests: IntCounterVec,
+    pub(crate) rtsp_sessions: IntGaugeVec,
+    pub(crate) media_packets: IntCounterVec,
+    pub(crate) media_bytes: IntCounterVec,
+    pub(crate) rejected_media: IntCounterVec,
+    pub(crate) interleaved_fragments: IntCounter,
+    pub(crate) demux_errors: IntCounter,
+    pub(crate) client_dropped_frames: IntCounterVec,
+    pub(crate) slow_client_disconnects: IntCounter,
+    pub(crate) session_timeouts: IntCounter,
+    pub(crate) cp_reconnects: IntCounter,
+    pub(crate) cp_queue_depth: IntGauge,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = match Registry::new_custom(Some(METRICS_NAMESPACE.to_string()), None) {
+            Ok(registry) => registry,
+            Err(_) => Registry::new(),
+        };
+
+        return Metrics {
+            active_flows: metrics_gauge(&registry, "active_flows", "Client flows currently open"),
+            accepted_connections: metrics_counter(&registry, "accepted_connections_total", "Client connections accepted"),
+            rtsp_requests: metrics_counter_vec(&registry, "rtsp_requests_total", "RTSP requests from clients", &["method"]),
+            rtsp_sessions: metrics_gauge_vec(&registry, "rtsp_sessions", "Client flows in each RTSP session state", &["state"]),
+            media_packets: metrics_counter_vec(&registry, "media_packets_total", "RTP/RTCP packets relayed", &["media", "direction"]),
+            media_bytes: metrics_counter_vec(&registry, "media_bytes_total", "RTP/RTCP payload bytes relayed", &["media", "direction"]),
+            rejected_media: metrics_counter_vec(&registry, "rejected_media_total", "RTP/RTCP packets from clients dropped for arriving outside PLAY or RECORD", &["media"]),
+            interleaved_fragments: metrics_counter(&registry, "interleaved_fragments_total", "Client reads that ended part way through an interleaved frame"),
+            demux_errors: metrics_counter(&registry, "demux_errors_total", "Client streams that could not be split into frames and messages"),
+            client_dropped_frames: metrics_counter_vec(&registry, "client_dropped_frames_total", "RTP/RTCP frames dropped for clients that fell behind", &["media"]),
+            slow_client_disconnects: metrics_counter(&registry, "slow_client_disconnects_total", "Clients disconnected for falling too far behind"),
+            session_timeouts: metrics_counter(&registry, "session_timeouts_total", "Client flows expired for going quiet beyond their RTSP session timeout"),
+            cp_reconnects: metrics_counter(&registry, "cp_reconnects_total", "Attempts to reconnect to the CP"),
+            cp_queue_depth: metrics_gauge(&registry, "cp_queue_depth", "Messages queued for the CP stream"),
+            registry,
+        }
+    }
+
+    /// count an RTSP request from a client, lumping extension methods together
+    pub(crate) fn rtsp_request(&self, method: &RtspMethod) {
+        let method = match method {
+            RtspMethod::Extension(_) => "OTHER".to_string(),
+            method => method.to_string(),
+        };
+        self.rtsp_requests.with_label_values(&[&method]).inc();
+    }
+
+    /// count a relayed RTP or RTCP packet
+    pub(crate) fn media(&self, media: &str, direction: &str, bytes: usize) {
+        self.media_packets.with_label_values(&[media, direction]).inc();
+        self.media_bytes.with_label_values(&[media, direction]).inc_by(bytes as u64);
+    }
+
+    /// all metrics in the Prometheus text format
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        match TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            Ok(()) => return Ok(buffer),
+            Err(e) => return Err(Error::other(e.to_string())),
+        }
+    }
+}
+
+// metric names are fixed and distinct, so creating and registering them only fails on a programming error
+
+fn metrics_counter(registry: &Registry, name: &str, help: &str) -> IntCounter {
+    let counter = IntCounter::new(name, help).expect("invalid counter");
+    registry.register(Box::new(counter.clone())).expect("duplicate counter");
+    return counter
+}
+
+fn metrics_counter_vec(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), labels).expect("invalid counter");
+    registry.register(Box::new(counter.clone())).expect("duplicate counter");
+    return counter
+}
+
+fn metrics_gauge(registry: &Registry, name: &str, help: &str) -> IntGauge {
+    let gauge = IntGauge::new(name, help).expect("invalid gauge");
+    registry.register(Box::new(gauge.clone())).expect("duplicate gauge");
+    return gauge
+}
+
+fn metrics_gauge_vec(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntGaugeVec {
+    let gauge = IntGaugeVec::new(Opts::new(name, help), labels).expect("invalid gauge");
+    registry.register(Box::new(gauge.clone())).expect("duplicate gauge");
+    return gauge
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
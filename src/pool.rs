@@ -0,0 +1,125 @@
+/*
+ * Copyright (c) 2022 Cisco and/or its affiliates.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at:
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::demux::{INTERLEAVED_HEADER_SIZE, INTERLEAVED_MAGIC};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// largest UDP payload, so a datagram is never truncated
+const DATAGRAM_MAX: usize = 65535;
+
+/// frames are carved out of slabs of this size
+const POOL_SLAB_SIZE: usize = 256 * 1024;
+
+/// Receive buffers for frames read from a UDP socket and sent on to an interleaved client
+///
+/// Each datagram is received straight into a slab after room for its interleaved header, which is filled in
+/// once the length is known, and the frame is handed out as a `Bytes` sharing the slab rather than a copy.
+/// When a slab runs out it is reused from the start if every frame carved from it has been written and dropped,
+/// otherwise a new one is allocated.
+#[derive(Debug, Default)]
+pub struct FramePool {
+    slab: BytesMut,
+}
+
+impl FramePool {
+    pub fn new() -> Self {
+        FramePool { slab: BytesMut::with_capacity(POOL_SLAB_SIZE) }
+    }
+
+    /// buffer to receive the next datagram for the channel into, with its header already in place
+    pub fn recv_buf(&mut self, channel: u8) -> &mut BytesMut {
+        // drop the header of a receive that failed
+        self.slab.clear();
+        if self.slab.capacity() < INTERLEAVED_HEADER_SIZE + DATAGRAM_MAX {
+            self.slab.reserve(POOL_SLAB_SIZE.max(INTERLEAVED_HEADER_SIZE + DATAGRAM_MAX));
+        }
+        self.slab.put_slice(&[INTERLEAVED_MAGIC, channel, 0, 0]);
+        &mut self.slab
+    }
+
+    /// the received datagram as a complete interleaved frame
+    pub fn frame(&mut self) -> Bytes {
+        let length = self.slab.len().saturating_sub(INTERLEAVED_HEADER_SIZE) as u16;
+        if let Some(header) = self.slab.get_mut(2..INTERLEAVED_HEADER_SIZE) {
+            header.copy_from_slice(&length.to_be_bytes());
+        }
+        return self.slab.split().freeze()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a frame as if the datagram had been received into the pool
+    fn received(pool: &mut FramePool, channel: u8, datagram: &[u8]) -> Bytes {
+        pool.recv_buf(channel).put_slice(datagram);
+        return pool.frame()
+    }
+
+    #[test]
+    fn frame_header_is_filled_in_place() {
+        let mut pool = FramePool::new();
+
+        let frame = received(&mut pool, 3, &[0x80; 300]);
+        assert_eq!(&frame[..INTERLEAVED_HEADER_SIZE], &[INTERLEAVED_MAGIC, 3, 0x01, 0x2c]);
+        assert_eq!(&frame[INTERLEAVED_HEADER_SIZE..], &[0x80; 300][..]);
+
+        let empty = received(&mut pool, 0, &[]);
+        assert_eq!(&empty[..], &[INTERLEAVED_MAGIC, 0, 0, 0]);
+    }
+
+    #[test]
+    fn frames_share_the_slab_until_dropped() {
+        let mut pool = FramePool::new();
+
+        // frames are carved one after another from the slab
+        let first = received(&mut pool, 0, b"first");
+        let second = received(&mut pool, 1, b"second");
+        assert_eq!(second.as_ptr(), first.as_ptr().wrapping_add(first.len()));
+        drop((first, second));
+
+        // four datagrams this size leave too little of a slab for another
+        let datagram = [0x80; 60000];
+
+        // so once they have been written and dropped, the slab is reused from the start
+        let mut pool = FramePool::new();
+        let start = received(&mut pool, 0, &datagram).as_ptr();
+        for _ in 1..4 {
+            received(&mut pool, 0, &datagram);
+        }
+        assert_eq!(received(&mut pool, 0, &datagram).as_ptr(), start);
+
+        // but not while a frame from it is still held
+        let mut pool = FramePool::new();
+        let held = received(&mut pool, 0, &datagram);
+        for _ in 1..4 {
+            received(&mut pool, 0, &datagram);
+        }
+        assert_ne!(received(&mut pool, 0, &datagram).as_ptr(), held.as_ptr());
+    }
+
+    #[test]
+    fn failed_receive_leaves_no_header_behind() {
+        let mut pool = FramePool::new();
+
+        // a receive that fails leaves its header in the buffer, which the next one replaces
+        pool.recv_buf(2);
+        let frame = received(&mut pool, 4, b"data");
+        assert_eq!(&frame[..], &[INTERLEAVED_MAGIC, 4, 0, 4, b'd', b'a', b't', b'a']);
+    }
+}
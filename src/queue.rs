@@ -0,0 +1,340 @@
+/*
+ * Copyright (c) 2022 Cisco and/or its affiliates.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at:
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::demux::INTERLEAVED_HEADER_SIZE;
+use crate::media::{rtp_payload, Codec};
+
+use bytes::{Buf, Bytes};
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use tracing::Span;
+
+/// queued bytes above which RTP frames are dropped rather than queued
+const QUEUE_RTP_HIGH_WATER: usize = 256 * 1024;
+
+/// queued bytes above which RTCP frames are dropped too
+const QUEUE_RTCP_HIGH_WATER: usize = 512 * 1024;
+
+/// queued bytes the client may fall behind by before it is disconnected
+const QUEUE_LIMIT: usize = 1024 * 1024;
+
+/// media frames dropped without an RTP frame getting through before the client is disconnected
+const QUEUE_MAX_DROPPED: usize = 1000;
+
+/// What a frame carries, which decides its lane and whether it may be dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// RTSP, written first and never dropped
+    Control,
+    /// interleaved RTCP, written before RTP and dropped whole
+    Rtcp,
+    /// interleaved RTP, written last and dropped whole
+    Rtp,
+}
+
+/// What happened to a frame offered to the queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Queued {
+    Queued,
+    /// media dropped because the client is behind
+    Dropped,
+    /// the client is too far behind to keep
+    Overflow,
+}
+
+/// a frame waiting to be written, with the span that ends once it has all gone
+#[derive(Debug)]
+struct QueuedFrame {
+    data: Bytes,
+    _span: Span,
+}
+
+/// Frames waiting to be written to a client, in a lane for each kind of frame
+///
+/// RTSP messages go first, then RTCP, then RTP. Frames are only ever written whole: once a frame has been
+/// started it is finished before anything else, so the interleaved stream is never corrupted.
+/// Once the client falls behind media frames are dropped whole, RTP first, while RTSP messages are always queued,
+/// and the client is disconnected if it falls too far behind. When the codec of an RTP stream is known,
+/// dropping a frame drops the rest of the stream up to its next keyframe rather than sending frames that can't be decoded.
+#[derive(Debug, Default)]
+pub struct ClientQueue {
+    control: VecDeque<QueuedFrame>,
+    rtcp: VecDeque<QueuedFrame>,
+    rtp: VecDeque<QueuedFrame>,
+    // frame being written, taken from the front of its lane
+    current: Option<QueuedFrame>,
+    bytes: usize,
+    // media frames dropped since the last RTP frame queued, RTCP getting through doesn't mean the client has caught up
+    dropped: usize,
+    // codecs by RTP payload type, from the SDP sent to the client
+    codecs: HashMap<u8, Codec>,
+    // interleaved channels whose RTP is dropped until the next keyframe
+    awaiting_keyframe: HashSet<u8>,
+}
+
+impl ClientQueue {
+    pub fn new() -> Self {
+        ClientQueue::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.current.is_none() && self.control.is_empty() && self.rtcp.is_empty() && self.rtp.is_empty()
+    }
+
+    /// bytes waiting to be written
+    pub fn queued(&self) -> usize {
+        self.bytes
+    }
+
+    /// codec of the RTP payload type, so its frames are dropped up to a keyframe
+    pub fn set_codec(&mut self, payload_type: u8, codec: Codec) {
+        self.codecs.insert(payload_type, codec);
+    }
+
+    /// count a media frame dropped because the client is behind, Overflow once too many have gone in a row
+    fn drop_frame(&mut self) -> Queued {
+        self.dropped += 1;
+        if self.dropped > QUEUE_MAX_DROPPED {
+            return Queued::Overflow
+        }
+        return Queued::Dropped
+    }
+
+    /// codec of an interleaved RTP frame, if known, and whether the frame starts a keyframe
+    fn rtp_keyframe(&self, data: &[u8]) -> (Option<Codec>, bool) {
+        let payload = data.get(INTERLEAVED_HEADER_SIZE..).and_then(rtp_payload);
+        match payload.and_then(|(payload_type, payload)| self.codecs.get(&payload_type).map(|codec| (*codec, payload))) {
+            Some((codec, payload)) => return (Some(codec), codec.starts_keyframe(payload)),
+            None => return (None, false),
+        }
+    }
+
+    /// queue a frame unless the client is behind and it can be dropped
+    pub fn push(&mut self, data: Bytes, kind: FrameKind, span: Span) -> Queued {
+        match kind {
+            FrameKind::Control => {
+                if self.bytes + data.len() > QUEUE_LIMIT {
+                    return Queued::Overflow
+                }
+            },
+            FrameKind::Rtcp => {
+                if self.bytes >= QUEUE_RTCP_HIGH_WATER {
+                    return self.drop_frame()
+                }
+            },
+            FrameKind::Rtp => {
+                let channel = data.get(1).copied().unwrap_or_default();
+                let (codec, keyframe) = self.rtp_keyframe(&data);
+                if self.bytes >= QUEUE_RTP_HIGH_WATER {
+                    if codec.is_some() {
+                        self.awaiting_keyframe.insert(channel);
+                    }
+                    return self.drop_frame()
+                }
+                if self.awaiting_keyframe.contains(&channel) {
+                    if !keyframe {
+                        return Queued::Dropped
+                    }
+                    self.awaiting_keyframe.remove(&channel);
+                }
+            },
+        }
+
+        if kind == FrameKind::Rtp {
+            self.dropped = 0;
+        }
+        self.bytes += data.len();
+        let frame = QueuedFrame { data, _span: span };
+        match kind {
+            FrameKind::Control => self.control.push_back(frame),
+            FrameKind::Rtcp => self.rtcp.push_back(frame),
+            FrameKind::Rtp => self.rtp.push_back(frame),
+        }
+        return Queued::Queued
+    }
+
+    /// unwritten part of the frame being written, starting the next one by priority if there is none
+    pub fn front(&mut self) -> Option<&[u8]> {
+        if self.current.is_none() {
+            self.current = self.control.pop_front()
+                .or_else(|| self.rtcp.pop_front())
+                .or_else(|| self.rtp.pop_front());
+        }
+        self.current.as_ref().map(|frame| &frame.data[..])
+    }
+
+    /// mark bytes of the frame being written as written, removing it once it has all gone
+    pub fn advance(&mut self, written: usize) {
+        if let Some(frame) = self.current.as_mut() {
+            let written = written.min(frame.data.len());
+            frame.data.advance(written);
+            self.bytes -= written;
+            if frame.data.is_empty() {
+                self.current = None;
+            }
+        }
+    }
+
+    /// drop all queued media except a frame already started, returning how many frames went
+    pub fn drop_media(&mut self) -> usize {
+        let dropped = self.rtcp.len() + self.rtp.len();
+        let dropped_bytes: usize = self.rtcp.drain(..).chain(self.rtp.drain(..)).map(|frame| frame.data.len()).sum();
+        self.bytes -= dropped_bytes;
+        return dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// interleaved H.264 RTP frame on the channel, payload type 96
+    fn rtp_frame(channel: u8, nal: &[u8]) -> Bytes {
+        let mut frame = vec![b'$', channel, 0, (12 + nal.len()) as u8, 0x80, 96, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        frame.extend_from_slice(nal);
+        return Bytes::from(frame)
+    }
+
+    const IDR: &[u8] = &[0x65, 0x88];
+    const SLICE: &[u8] = &[0x41, 0x9a];
+
+    fn push(queue: &mut ClientQueue, data: &Bytes, kind: FrameKind) -> Queued {
+        queue.push(data.clone(), kind, Span::none())
+    }
+
+    /// queue RTSP until the client is the given number of bytes behind
+    fn fill_to(queue: &mut ClientQueue, bytes: usize) {
+        let filler = Bytes::from(vec![b'R'; bytes - queue.queued()]);
+        assert_eq!(push(queue, &filler, FrameKind::Control), Queued::Queued);
+    }
+
+    /// write everything queued, returning the frames in the order written
+    fn drain(queue: &mut ClientQueue) -> Vec<Bytes> {
+        let mut written = Vec::new();
+        while let Some(frame) = queue.front() {
+            written.push(Bytes::copy_from_slice(frame));
+            let length = frame.len();
+            queue.advance(length);
+        }
+        assert_eq!(queue.queued(), 0);
+        return written
+    }
+
+    #[test]
+    fn lanes_are_written_by_priority() {
+        let mut queue = ClientQueue::new();
+        let rtp = rtp_frame(0, SLICE);
+        let rtcp = Bytes::from_static(b"$\x01\x00\x04\x81\xc9\x00\x00");
+        let rtsp = Bytes::from_static(b"RTSP/1.0 200 OK\r\nCSeq: 1\r\n\r\n");
+
+        assert_eq!(push(&mut queue, &rtp, FrameKind::Rtp), Queued::Queued);
+        assert_eq!(push(&mut queue, &rtcp, FrameKind::Rtcp), Queued::Queued);
+        assert_eq!(push(&mut queue, &rtsp, FrameKind::Control), Queued::Queued);
+        assert_eq!(queue.queued(), rtp.len() + rtcp.len() + rtsp.len());
+        assert_eq!(drain(&mut queue), vec![rtsp, rtcp, rtp]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn started_frame_is_finished_first() {
+        let mut queue = ClientQueue::new();
+        let rtp = rtp_frame(0, SLICE);
+        let rtsp = Bytes::from_static(b"RTSP/1.0 200 OK\r\nCSeq: 2\r\n\r\n");
+
+        push(&mut queue, &rtp, FrameKind::Rtp);
+        assert_eq!(queue.front(), Some(&rtp[..]));
+        queue.advance(3);
+
+        // RTSP queued mid-frame waits, and dropping media leaves the started frame alone
+        push(&mut queue, &rtsp, FrameKind::Control);
+        assert_eq!(queue.drop_media(), 0);
+        assert_eq!(queue.front(), Some(&rtp[3..]));
+        assert_eq!(drain(&mut queue), vec![rtp.slice(3..), rtsp]);
+    }
+
+    #[test]
+    fn media_is_dropped_once_the_client_is_behind() {
+        let mut queue = ClientQueue::new();
+        let rtp = rtp_frame(0, SLICE);
+        let rtcp = Bytes::from_static(b"$\x01\x00\x04\x81\xc9\x00\x00");
+        fill_to(&mut queue, QUEUE_RTP_HIGH_WATER);
+
+        // RTP goes first, RTCP keeps flowing until its own high water mark
+        assert_eq!(push(&mut queue, &rtp, FrameKind::Rtp), Queued::Dropped);
+        assert_eq!(push(&mut queue, &rtcp, FrameKind::Rtcp), Queued::Queued);
+        fill_to(&mut queue, QUEUE_RTCP_HIGH_WATER);
+        assert_eq!(push(&mut queue, &rtcp, FrameKind::Rtcp), Queued::Dropped);
+
+        // RTSP is queued until the client is too far behind to keep
+        let rtsp = Bytes::from_static(b"RTSP/1.0 200 OK\r\nCSeq: 3\r\n\r\n");
+        assert_eq!(push(&mut queue, &rtsp, FrameKind::Control), Queued::Queued);
+        fill_to(&mut queue, QUEUE_LIMIT);
+        assert_eq!(push(&mut queue, &rtsp, FrameKind::Control), Queued::Overflow);
+    }
+
+    #[test]
+    fn dropped_rtp_waits_for_a_keyframe() {
+        let mut queue = ClientQueue::new();
+        queue.set_codec(96, Codec::H264);
+        fill_to(&mut queue, QUEUE_RTP_HIGH_WATER);
+        assert_eq!(push(&mut queue, &rtp_frame(0, SLICE), FrameKind::Rtp), Queued::Dropped);
+        drain(&mut queue);
+
+        // the client has caught up, but frames that depend on the dropped one still go
+        assert_eq!(push(&mut queue, &rtp_frame(0, SLICE), FrameKind::Rtp), Queued::Dropped);
+        // while other channels carry on
+        assert_eq!(push(&mut queue, &rtp_frame(2, SLICE), FrameKind::Rtp), Queued::Queued);
+        assert_eq!(push(&mut queue, &rtp_frame(0, IDR), FrameKind::Rtp), Queued::Queued);
+        assert_eq!(push(&mut queue, &rtp_frame(0, SLICE), FrameKind::Rtp), Queued::Queued);
+    }
+
+    #[test]
+    fn unknown_codecs_resume_at_once() {
+        let mut queue = ClientQueue::new();
+        fill_to(&mut queue, QUEUE_RTP_HIGH_WATER);
+        assert_eq!(push(&mut queue, &rtp_frame(0, SLICE), FrameKind::Rtp), Queued::Dropped);
+        drain(&mut queue);
+        assert_eq!(push(&mut queue, &rtp_frame(0, SLICE), FrameKind::Rtp), Queued::Queued);
+    }
+
+    #[test]
+    fn queued_rtp_resets_the_dropped_count() {
+        let mut queue = ClientQueue::new();
+        let rtp = rtp_frame(0, SLICE);
+        let rtcp = Bytes::from_static(b"$\x01\x00\x04\x81\xc9\x00\x00");
+
+        fill_to(&mut queue, QUEUE_RTCP_HIGH_WATER);
+        for _ in 0..QUEUE_MAX_DROPPED {
+            assert_eq!(push(&mut queue, &rtp, FrameKind::Rtp), Queued::Dropped);
+        }
+        drain(&mut queue);
+
+        // RTCP getting through doesn't mean the client has caught up
+        assert_eq!(push(&mut queue, &rtcp, FrameKind::Rtcp), Queued::Queued);
+        fill_to(&mut queue, QUEUE_RTCP_HIGH_WATER);
+        assert_eq!(push(&mut queue, &rtcp, FrameKind::Rtcp), Queued::Overflow);
+        drain(&mut queue);
+
+        // but RTP does
+        assert_eq!(push(&mut queue, &rtp, FrameKind::Rtp), Queued::Queued);
+        fill_to(&mut queue, QUEUE_RTCP_HIGH_WATER);
+        for _ in 0..QUEUE_MAX_DROPPED {
+            assert_eq!(push(&mut queue, &rtp, FrameKind::Rtp), Queued::Dropped);
+        }
+        assert_eq!(push(&mut queue, &rtp, FrameKind::Rtp), Queued::Overflow);
+    }
+}
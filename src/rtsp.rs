@@ -0,0 +1,878 @@
+/*
+ * Copyright (c) 2022 Cisco and/or its affiliates.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at:
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::str::FromStr;
+
+const RTSP_VERSION: &str = "RTSP/1.0";
+
+/// largest start line plus headers we will buffer before giving up on a client
+const RTSP_MAX_HEADER_SIZE: usize = 65536;
+
+/// largest body we will accept in a single RTSP message
+const RTSP_MAX_BODY_SIZE: usize = 1048576;
+
+/// skip any empty lines sent between RTSP messages
+pub fn rtsp_skip_empty_lines(data: &[u8]) -> usize {
+    let mut skipped = 0;
+    while skipped < data.len() && (data[skipped] == b'\r' || data[skipped] == b'\n') {
+        skipped += 1;
+    }
+    return skipped
+}
+
+/// find the end of the start line and headers (offset of the first body byte)
+fn rtsp_header_end(data: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    while let Some(offset) = data[start..].iter().position(|&byte| byte == b'\n') {
+        let line_end = start + offset;
+        let line = &data[start..line_end];
+
+        // an empty line (CRLF or bare LF) terminates the headers
+        if line.is_empty() || line == b"\r" {
+            return Some(line_end + 1)
+        }
+        start = line_end + 1;
+    }
+    return None
+}
+
+/// parse the Content-Length header (if any) out of the header block
+fn rtsp_content_length(headers: &[u8]) -> Result<usize> {
+    // skip the start line
+    for line in headers.split(|&byte| byte == b'\n').skip(1) {
+        if let Some(colon) = line.iter().position(|&byte| byte == b':') {
+            let name = String::from_utf8_lossy(&line[..colon]);
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                let value = String::from_utf8_lossy(&line[colon+1..]);
+                match value.trim().parse::<usize>() {
+                    Ok(length) if length <= RTSP_MAX_BODY_SIZE => return Ok(length),
+                    Ok(length) => return Err(Error::new(ErrorKind::InvalidData, format!("Content-Length {} too large", length))),
+                    Err(e) => return Err(Error::new(ErrorKind::InvalidData, format!("invalid Content-Length: {}", e))),
+                }
+            }
+        }
+    }
+    return Ok(0)
+}
+
+/// Find the length of the first complete RTSP message (start line, headers and body)
+/// at the front of the buffer, or None if more data is needed to complete it
+pub fn rtsp_message_len(data: &[u8]) -> Result<Option<usize>> {
+    match rtsp_header_end(data) {
+        Some(header_end) => {
+            if header_end > RTSP_MAX_HEADER_SIZE {
+                return Err(Error::new(ErrorKind::InvalidData, "RTSP headers too long"))
+            }
+
+            match rtsp_content_length(&data[..header_end]) {
+                Ok(body_length) => {
+                    let total = header_end + body_length;
+                    if data.len() < total {
+                        return Ok(None)
+                    }
+                    return Ok(Some(total))
+                },
+                Err(e) => return Err(e),
+            }
+        },
+        None => {
+            if data.len() > RTSP_MAX_HEADER_SIZE {
+                return Err(Error::new(ErrorKind::InvalidData, "RTSP headers too long"))
+            }
+            return Ok(None)
+        },
+    }
+}
+
+/// RTSP methods (RFC 2326)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RtspMethod {
+    Describe,
+    Announce,
+    GetParameter,
+    Options,
+    Pause,
+    Play,
+    Record,
+    Redirect,
+    Setup,
+    SetParameter,
+    Teardown,
+    Extension(String),
+}
+
+impl FromStr for RtspMethod {
+    type Err = Error;
+
+    fn from_str(method: &str) -> Result<Self> {
+        if method.is_empty() || !method.bytes().all(|byte| byte.is_ascii_graphic()) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("invalid RTSP method {:?}", method)))
+        }
+
+        let parsed = match method {
+            "DESCRIBE" => RtspMethod::Describe,
+            "ANNOUNCE" => RtspMethod::Announce,
+            "GET_PARAMETER" => RtspMethod::GetParameter,
+            "OPTIONS" => RtspMethod::Options,
+            "PAUSE" => RtspMethod::Pause,
+            "PLAY" => RtspMethod::Play,
+            "RECORD" => RtspMethod::Record,
+            "REDIRECT" => RtspMethod::Redirect,
+            "SETUP" => RtspMethod::Setup,
+            "SET_PARAMETER" => RtspMethod::SetParameter,
+            "TEARDOWN" => RtspMethod::Teardown,
+            other => RtspMethod::Extension(other.to_string()),
+        };
+        return Ok(parsed)
+    }
+}
+
+impl fmt::Display for RtspMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let method = match self {
+            RtspMethod::Describe => "DESCRIBE",
+            RtspMethod::Announce => "ANNOUNCE",
+            RtspMethod::GetParameter => "GET_PARAMETER",
+            RtspMethod::Options => "OPTIONS",
+            RtspMethod::Pause => "PAUSE",
+            RtspMethod::Play => "PLAY",
+            RtspMethod::Record => "RECORD",
+            RtspMethod::Redirect => "REDIRECT",
+            RtspMethod::Setup => "SETUP",
+            RtspMethod::SetParameter => "SET_PARAMETER",
+            RtspMethod::Teardown => "TEARDOWN",
+            RtspMethod::Extension(other) => other,
+        };
+        write!(f, "{}", method)
+    }
+}
+
+/// RTSP headers, kept in the order received and looked up case-insensitively
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RtspHeaders {
+    headers: Vec<(String, String)>,
+}
+
+impl RtspHeaders {
+    pub fn new() -> Self {
+        RtspHeaders { headers: Vec::new() }
+    }
+
+    /// first value for the header, if present
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// all values for the header, in the order received
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers.iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// add a header, keeping any existing values
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// replace the first value of the header (in place), removing any others, or append it
+    pub fn set(&mut self, name: &str, value: &str) {
+        match self.headers.iter().position(|(key, _)| key.eq_ignore_ascii_case(name)) {
+            Some(index) => {
+                self.headers[index].1 = value.to_string();
+                let mut seen = 0;
+                self.headers.retain(|(key, _)| {
+                    if key.eq_ignore_ascii_case(name) {
+                        seen += 1;
+                        return seen == 1
+                    }
+                    return true
+                });
+            },
+            None => self.append(name, value),
+        }
+    }
+
+    /// remove all values of the header, returning the first
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let first = self.get(name).map(|value| value.to_string());
+        self.headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        return first
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    /// CSeq header as a number
+    pub fn cseq(&self) -> Option<u32> {
+        self.get("CSeq").and_then(|value| value.trim().parse().ok())
+    }
+
+    /// Session header without any parameters (e.g. ";timeout=60")
+    pub fn session_id(&self) -> Option<&str> {
+        self.get("Session").and_then(|value| value.split(';').next()).map(|id| id.trim())
+    }
+
+    /// timeout parameter of the Session header, in seconds
+    pub fn session_timeout(&self) -> Option<u64> {
+        self.get("Session").and_then(|value| {
+            value.split(';').skip(1)
+                .filter_map(|param| param.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("timeout"))
+                .and_then(|(_, timeout)| timeout.trim().parse().ok())
+        })
+    }
+
+    pub fn transport(&self) -> Option<&str> {
+        self.get("Transport")
+    }
+
+    fn parse(lines: &[&str]) -> Result<Self> {
+        let mut headers = RtspHeaders::new();
+        for line in lines {
+            // folded continuation of the previous header
+            if line.starts_with(' ') || line.starts_with('\t') {
+                match headers.headers.last_mut() {
+                    Some((_, value)) => {
+                        value.push(' ');
+                        value.push_str(line.trim());
+                        continue
+                    },
+                    None => return Err(Error::new(ErrorKind::InvalidData, "RTSP header continuation without header")),
+                }
+            }
+
+            match line.split_once(':') {
+                Some((name, value)) if !name.trim().is_empty() => headers.append(name.trim(), value.trim()),
+                _ => return Err(Error::new(ErrorKind::InvalidData, format!("invalid RTSP header {:?}", line))),
+            }
+        }
+        return Ok(headers)
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        for (name, value) in &self.headers {
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+}
+
+/// RTSP request from a client (or server)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtspRequest {
+    pub method: RtspMethod,
+    pub uri: String,
+    pub version: String,
+    pub headers: RtspHeaders,
+    pub body: Vec<u8>,
+}
+
+/// RTSP response to a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtspResponse {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: RtspHeaders,
+    pub body: Vec<u8>,
+}
+
+/// Either kind of RTSP message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RtspMessage {
+    Request(RtspRequest),
+    Response(RtspResponse),
+}
+
+impl RtspRequest {
+    pub fn new(method: RtspMethod, uri: &str) -> Self {
+        RtspRequest {
+            method,
+            uri: uri.to_string(),
+            version: RTSP_VERSION.to_string(),
+            headers: RtspHeaders::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// serialize the request, setting Content-Length to match the body
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{} {} {}\r\n", self.method, self.uri, self.version).into_bytes();
+        rtsp_write_tail(&self.headers, &self.body, &mut out);
+        return out
+    }
+}
+
+impl RtspResponse {
+    pub fn new(status: u16, reason: &str) -> Self {
+        RtspResponse {
+            version: RTSP_VERSION.to_string(),
+            status,
+            reason: reason.to_string(),
+            headers: RtspHeaders::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// serialize the response, setting Content-Length to match the body
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{} {} {}\r\n", self.version, self.status, self.reason).into_bytes();
+        rtsp_write_tail(&self.headers, &self.body, &mut out);
+        return out
+    }
+}
+
+impl RtspMessage {
+    /// parse one complete RTSP message, as framed by rtsp_message_len
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let header_end = match rtsp_header_end(data) {
+            Some(header_end) => header_end,
+            None => return Err(Error::new(ErrorKind::UnexpectedEof, "incomplete RTSP headers")),
+        };
+
+        let head = match std::str::from_utf8(&data[..header_end]) {
+            Ok(head) => head,
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e.to_string())),
+        };
+
+        let lines: Vec<&str> = head.lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .filter(|line| !line.is_empty())
+            .collect();
+
+        let start_line = match lines.first() {
+            Some(start_line) => *start_line,
+            None => return Err(Error::new(ErrorKind::InvalidData, "missing RTSP start line")),
+        };
+
+        let headers = match RtspHeaders::parse(&lines[1..]) {
+            Ok(headers) => headers,
+            Err(e) => return Err(e),
+        };
+
+        let body_length = match rtsp_content_length(&data[..header_end]) {
+            Ok(body_length) => body_length,
+            Err(e) => return Err(e),
+        };
+        if data.len() < header_end + body_length {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "incomplete RTSP body"))
+        }
+        let body = data[header_end..header_end + body_length].to_vec();
+
+        let mut parts = start_line.splitn(3, ' ');
+        let first = parts.next().unwrap_or_default();
+        let second = parts.next().unwrap_or_default();
+        let third = parts.next().unwrap_or_default();
+
+        if first.starts_with("RTSP/") {
+            match second.parse::<u16>() {
+                Ok(status) => {
+                    return Ok(RtspMessage::Response(RtspResponse {
+                        version: first.to_string(),
+                        status,
+                        reason: third.to_string(),
+                        headers,
+                        body,
+                    }))
+                },
+                Err(e) => return Err(Error::new(ErrorKind::InvalidData, format!("invalid RTSP status: {}", e))),
+            }
+        }
+
+        if second.is_empty() || !third.starts_with("RTSP/") {
+            return Err(Error::new(ErrorKind::InvalidData, format!("invalid RTSP request line {:?}", start_line)))
+        }
+
+        match RtspMethod::from_str(first) {
+            Ok(method) => {
+                return Ok(RtspMessage::Request(RtspRequest {
+                    method,
+                    uri: second.to_string(),
+                    version: third.to_string(),
+                    headers,
+                    body,
+                }))
+            },
+            Err(e) => return Err(e),
+        }
+    }
+
+    pub fn headers(&self) -> &RtspHeaders {
+        match self {
+            RtspMessage::Request(request) => &request.headers,
+            RtspMessage::Response(response) => &response.headers,
+        }
+    }
+
+    pub fn headers_mut(&mut self) -> &mut RtspHeaders {
+        match self {
+            RtspMessage::Request(request) => &mut request.headers,
+            RtspMessage::Response(response) => &mut response.headers,
+        }
+    }
+
+    pub fn body(&self) -> &[u8] {
+        match self {
+            RtspMessage::Request(request) => &request.body,
+            RtspMessage::Response(response) => &response.body,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            RtspMessage::Request(request) => request.to_bytes(),
+            RtspMessage::Response(response) => response.to_bytes(),
+        }
+    }
+}
+
+impl fmt::Display for RtspMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RtspMessage::Request(request) => write!(f, "{} {}", request.method, request.uri)?,
+            RtspMessage::Response(response) => write!(f, "{} {}", response.status, response.reason)?,
+        }
+        match self.headers().cseq() {
+            Some(cseq) => write!(f, " (CSeq {})", cseq),
+            None => Ok(()),
+        }
+    }
+}
+
+/// write headers, Content-Length, blank line and body
+fn rtsp_write_tail(headers: &RtspHeaders, body: &[u8], out: &mut Vec<u8>) {
+    let mut headers = headers.clone();
+    if body.is_empty() {
+        headers.remove("Content-Length");
+    } else {
+        headers.set("Content-Length", &body.len().to_string());
+    }
+    headers.write(out);
+    out.extend_from_slice(b"\r\n");
+    out.extend_from_slice(body);
+}
+
+/// One transport spec from a Transport header (RFC 2326 section 12.39)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtspTransport {
+    /// e.g. RTP/AVP, RTP/AVP/UDP or RTP/AVP/TCP
+    pub protocol: String,
+    /// parameters in the order received, with None for flags such as "unicast"
+    pub params: Vec<(String, Option<String>)>,
+}
+
+impl RtspTransport {
+    /// parse all the comma-separated transport specs in a Transport header value
+    pub fn parse_header(value: &str) -> Result<Vec<RtspTransport>> {
+        let mut transports = Vec::new();
+        for spec in value.split(',').map(|spec| spec.trim()).filter(|spec| !spec.is_empty()) {
+            match RtspTransport::from_str(spec) {
+                Ok(transport) => transports.push(transport),
+                Err(e) => return Err(e),
+            }
+        }
+        if transports.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "empty RTSP Transport header"))
+        }
+        return Ok(transports)
+    }
+
+    /// serialize transport specs back into a Transport header value
+    pub fn to_header(transports: &[RtspTransport]) -> String {
+        transports.iter().map(|transport| transport.to_string()).collect::<Vec<String>>().join(",")
+    }
+
+    /// value of a parameter (Some("") for a flag)
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_deref().unwrap_or(""))
+    }
+
+    /// set a parameter (or a flag if value is None), replacing any existing value in place
+    pub fn set_param(&mut self, name: &str, value: Option<&str>) {
+        let value = value.map(|value| value.to_string());
+        match self.params.iter_mut().find(|(key, _)| key.eq_ignore_ascii_case(name)) {
+            Some(param) => param.1 = value,
+            None => self.params.push((name.to_string(), value)),
+        }
+    }
+
+    pub fn remove_param(&mut self, name: &str) {
+        self.params.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    /// RTP carried on the RTSP connection
+    pub fn is_tcp(&self) -> bool {
+        self.protocol.to_ascii_uppercase().ends_with("/TCP")
+    }
+
+    /// interleaved RTP and RTCP channels
+    pub fn interleaved(&self) -> Option<(u8, u8)> {
+        self.param("interleaved").and_then(rtsp_transport_range)
+    }
+
+    /// client RTP and RTCP ports
+    pub fn client_port(&self) -> Option<(u16, u16)> {
+        self.param("client_port").and_then(rtsp_transport_range)
+    }
+
+    /// server RTP and RTCP ports
+    pub fn server_port(&self) -> Option<(u16, u16)> {
+        self.param("server_port").and_then(rtsp_transport_range)
+    }
+}
+
+impl FromStr for RtspTransport {
+    type Err = Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let mut parts = spec.split(';').map(|part| part.trim());
+        let protocol = match parts.next() {
+            Some(protocol) if protocol.to_ascii_uppercase().starts_with("RTP/") => protocol.to_string(),
+            _ => return Err(Error::new(ErrorKind::InvalidData, format!("unsupported RTSP transport {:?}", spec))),
+        };
+
+        let params = parts
+            .filter(|part| !part.is_empty())
+            .map(|part| match part.split_once('=') {
+                Some((name, value)) => (name.trim().to_string(), Some(value.trim().to_string())),
+                None => (part.to_string(), None),
+            })
+            .collect();
+
+        return Ok(RtspTransport { protocol, params })
+    }
+}
+
+impl fmt::Display for RtspTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.protocol)?;
+        for (name, value) in &self.params {
+            match value {
+                Some(value) => write!(f, ";{}={}", name, value)?,
+                None => write!(f, ";{}", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// parse "a-b" (or just "a", meaning "a-(a+1)") as used by interleaved and the port parameters
+fn rtsp_transport_range<T: TryFrom<u32>>(value: &str) -> Option<(T, T)> {
+    let (first, second) = match value.split_once('-') {
+        Some((first, second)) => (first.trim().parse::<u32>().ok()?, second.trim().parse::<u32>().ok()?),
+        None => {
+            let first = value.trim().parse::<u32>().ok()?;
+            (first, first.checked_add(1)?)
+        },
+    };
+    return Some((T::try_from(first).ok()?, T::try_from(second).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: &[u8] = b"OPTIONS rtsp://camera/stream RTSP/1.0\r\nCSeq: 1\r\n\r\n";
+    const ANNOUNCE: &[u8] = b"ANNOUNCE rtsp://camera/stream RTSP/1.0\r\nCSeq: 2\r\nContent-Length: 5\r\n\r\nv=0\r\n";
+
+    fn error_kind(result: Result<Option<usize>>) -> ErrorKind {
+        match result {
+            Ok(length) => panic!("framed as {:?}", length),
+            Err(e) => return e.kind(),
+        }
+    }
+
+    #[test]
+    fn message_len_waits_for_split_start_line_and_headers() {
+        for split in 0..OPTIONS.len() {
+            assert_eq!(rtsp_message_len(&OPTIONS[..split]).unwrap(), None, "split at {}", split);
+        }
+        assert_eq!(rtsp_message_len(OPTIONS).unwrap(), Some(OPTIONS.len()));
+    }
+
+    #[test]
+    fn message_len_waits_for_split_body() {
+        let header_end = ANNOUNCE.len() - 5;
+        assert_eq!(rtsp_header_end(ANNOUNCE), Some(header_end));
+        for split in header_end..ANNOUNCE.len() {
+            assert_eq!(rtsp_message_len(&ANNOUNCE[..split]).unwrap(), None, "split at {}", split);
+        }
+        assert_eq!(rtsp_message_len(ANNOUNCE).unwrap(), Some(ANNOUNCE.len()));
+    }
+
+    #[test]
+    fn message_len_frames_pipelined_messages_one_at_a_time() {
+        let pipelined = [ANNOUNCE, OPTIONS].concat();
+        assert_eq!(rtsp_message_len(&pipelined).unwrap(), Some(ANNOUNCE.len()));
+        assert_eq!(rtsp_message_len(&pipelined[ANNOUNCE.len()..]).unwrap(), Some(OPTIONS.len()));
+    }
+
+    #[test]
+    fn message_len_accepts_bare_lf_line_endings() {
+        let message = b"ANNOUNCE rtsp://camera/stream RTSP/1.0\nCSeq: 2\ncontent-length: 4\n\nv=0\nOPTIONS";
+        assert_eq!(rtsp_header_end(message), Some(message.len() - 11));
+        assert_eq!(rtsp_content_length(&message[..message.len() - 11]).unwrap(), 4);
+        assert_eq!(rtsp_message_len(message).unwrap(), Some(message.len() - 7));
+    }
+
+    #[test]
+    fn content_length_defaults_to_zero() {
+        assert_eq!(rtsp_content_length(OPTIONS).unwrap(), 0);
+        // the start line is never taken for a header
+        assert_eq!(rtsp_content_length(b"Content-Length: 9 x RTSP/1.0\r\n\r\n").unwrap(), 0);
+    }
+
+    #[test]
+    fn message_len_rejects_oversized_content_length() {
+        let message = format!("ANNOUNCE rtsp://camera/stream RTSP/1.0\r\nContent-Length: {}\r\n\r\n", RTSP_MAX_BODY_SIZE + 1);
+        assert_eq!(error_kind(rtsp_message_len(message.as_bytes())), ErrorKind::InvalidData);
+
+        let message = format!("ANNOUNCE rtsp://camera/stream RTSP/1.0\r\nContent-Length: {}\r\n\r\n", RTSP_MAX_BODY_SIZE);
+        assert_eq!(rtsp_message_len(message.as_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn message_len_rejects_non_numeric_content_length() {
+        for length in ["five", "-1", "", "5 5"] {
+            let message = format!("ANNOUNCE rtsp://camera/stream RTSP/1.0\r\nContent-Length: {}\r\n\r\n", length);
+            assert_eq!(error_kind(rtsp_message_len(message.as_bytes())), ErrorKind::InvalidData, "Content-Length {:?}", length);
+        }
+    }
+
+    #[test]
+    fn message_len_rejects_headers_over_the_limit() {
+        let mut message = b"OPTIONS rtsp://camera/stream RTSP/1.0\r\n".to_vec();
+        while message.len() <= RTSP_MAX_HEADER_SIZE {
+            message.extend_from_slice(b"X-Padding: padding\r\n");
+        }
+
+        // without a terminator the stub gives up rather than buffering forever
+        assert_eq!(error_kind(rtsp_message_len(&message)), ErrorKind::InvalidData);
+
+        // and with one the headers are still too long
+        message.extend_from_slice(b"\r\n");
+        assert_eq!(error_kind(rtsp_message_len(&message)), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn message_len_buffers_headers_up_to_the_limit() {
+        let mut message = b"OPTIONS rtsp://camera/stream RTSP/1.0\r\n".to_vec();
+        message.resize(RTSP_MAX_HEADER_SIZE, b'x');
+        assert_eq!(rtsp_message_len(&message).unwrap(), None);
+    }
+
+    fn parse(data: &[u8]) -> RtspMessage {
+        match RtspMessage::parse(data) {
+            Ok(message) => return message,
+            Err(e) => panic!("unable to parse {:?}: {}", String::from_utf8_lossy(data), e),
+        }
+    }
+
+    #[test]
+    fn request_round_trips() {
+        let message = parse(ANNOUNCE);
+        match &message {
+            RtspMessage::Request(request) => {
+                assert_eq!(request.method, RtspMethod::Announce);
+                assert_eq!(request.uri, "rtsp://camera/stream");
+                assert_eq!(request.version, "RTSP/1.0");
+                assert_eq!(request.body, b"v=0\r\n");
+            },
+            RtspMessage::Response(_) => panic!("parsed as a response"),
+        }
+        assert_eq!(message.to_bytes(), ANNOUNCE);
+        assert_eq!(parse(OPTIONS).to_bytes(), OPTIONS);
+    }
+
+    #[test]
+    fn response_round_trips() {
+        let data = b"RTSP/1.0 454 Session Not Found\r\nCSeq: 3\r\nSession: 12345678\r\n\r\n";
+        let message = parse(data);
+        match &message {
+            RtspMessage::Response(response) => {
+                assert_eq!(response.status, 454);
+                assert_eq!(response.reason, "Session Not Found");
+                assert!(response.body.is_empty());
+            },
+            RtspMessage::Request(_) => panic!("parsed as a request"),
+        }
+        assert_eq!(message.to_string(), "454 Session Not Found (CSeq 3)");
+        assert_eq!(message.to_bytes(), data);
+    }
+
+    #[test]
+    fn extension_methods_round_trip() {
+        let data = b"FLUSH rtsp://camera/stream RTSP/1.0\r\nCSeq: 4\r\n\r\n";
+        match parse(data) {
+            RtspMessage::Request(request) => {
+                assert_eq!(request.method, RtspMethod::Extension("FLUSH".to_string()));
+                assert_eq!(request.to_bytes(), data);
+            },
+            RtspMessage::Response(_) => panic!("parsed as a response"),
+        }
+    }
+
+    #[test]
+    fn serializer_sets_content_length_from_the_body() {
+        let mut response = RtspResponse::new(200, "OK");
+        response.headers.append("CSeq", "2");
+        response.headers.append("Content-Length", "99");
+        assert_eq!(response.to_bytes(), b"RTSP/1.0 200 OK\r\nCSeq: 2\r\n\r\n");
+
+        response.body = b"v=0\r\n".to_vec();
+        assert_eq!(response.to_bytes(), b"RTSP/1.0 200 OK\r\nCSeq: 2\r\nContent-Length: 5\r\n\r\nv=0\r\n");
+    }
+
+    #[test]
+    fn parse_rejects_malformed_messages() {
+        for data in [
+            &b"OPTIONS rtsp://camera/stream RTSP/1.0\r\nCSeq: 1\r\n"[..],
+            b"OPTIONS rtsp://camera/stream HTTP/1.1\r\n\r\n",
+            b"OPTIONS\r\n\r\n",
+            b"RTSP/1.0 OK\r\n\r\n",
+            b"OPTIONS rtsp://camera/stream RTSP/1.0\r\nCSeq 1\r\n\r\n",
+            b"OPTIONS rtsp://camera/stream RTSP/1.0\r\n continued\r\n\r\n",
+            b"ANNOUNCE rtsp://camera/stream RTSP/1.0\r\nContent-Length: 5\r\n\r\nv=0",
+        ] {
+            assert!(RtspMessage::parse(data).is_err(), "parsed {:?}", String::from_utf8_lossy(data));
+        }
+    }
+
+    #[test]
+    fn headers_are_looked_up_case_insensitively() {
+        let message = parse(b"SETUP rtsp://camera/stream RTSP/1.0\r\ncseq: 5\r\nTRANSPORT: RTP/AVP;unicast\r\nx-custom: one\r\nX-Custom: two\r\n\r\n");
+        let headers = message.headers();
+        assert_eq!(headers.cseq(), Some(5));
+        assert_eq!(headers.transport(), Some("RTP/AVP;unicast"));
+        assert_eq!(headers.get("X-CUSTOM"), Some("one"));
+        assert_eq!(headers.get_all("x-custom").collect::<Vec<&str>>(), ["one", "two"]);
+
+        let mut headers = headers.clone();
+        headers.set("X-CUSTOM", "three");
+        assert_eq!(headers.get_all("x-custom").collect::<Vec<&str>>(), ["three"]);
+        assert_eq!(headers.iter().nth(2), Some(("x-custom", "three")));
+        assert_eq!(headers.remove("Transport").as_deref(), Some("RTP/AVP;unicast"));
+        assert!(!headers.contains("transport"));
+    }
+
+    #[test]
+    fn folded_headers_are_joined() {
+        let message = parse(b"SETUP rtsp://camera/stream RTSP/1.0\r\nCSeq: 6\r\nTransport: RTP/AVP;unicast;\r\n  client_port=5000-5001\r\n\tmode=play\r\n\r\n");
+        assert_eq!(message.headers().transport(), Some("RTP/AVP;unicast; client_port=5000-5001 mode=play"));
+        assert_eq!(message.headers().len(), 2);
+    }
+
+    #[test]
+    fn session_header_parameters() {
+        let message = parse(b"RTSP/1.0 200 OK\r\nCSeq: 1\r\nSession: 12345678 ; Timeout = 30\r\n\r\n");
+        assert_eq!(message.headers().session_id(), Some("12345678"));
+        assert_eq!(message.headers().session_timeout(), Some(30));
+
+        let message = parse(b"RTSP/1.0 200 OK\r\nCSeq: 1\r\nSession: 12345678\r\n\r\n");
+        assert_eq!(message.headers().session_id(), Some("12345678"));
+        assert_eq!(message.headers().session_timeout(), None);
+
+        let message = parse(b"RTSP/1.0 200 OK\r\nCSeq: 1\r\nSession: 12345678;timeout=soon\r\n\r\n");
+        assert_eq!(message.headers().session_timeout(), None);
+        assert_eq!(parse(OPTIONS).headers().session_id(), None);
+    }
+
+    #[test]
+    fn transport_parses_interleaved_channels() {
+        let transports = RtspTransport::parse_header("RTP/AVP/TCP;unicast;interleaved=2-3").unwrap();
+        assert_eq!(transports.len(), 1);
+        assert!(transports[0].is_tcp());
+        assert_eq!(transports[0].param("unicast"), Some(""));
+        assert_eq!(transports[0].interleaved(), Some((2, 3)));
+        assert_eq!(transports[0].client_port(), None);
+
+        // channels must fit in the one byte of the interleaved frame header
+        let transports = RtspTransport::parse_header("RTP/AVP/TCP;interleaved=255-256").unwrap();
+        assert_eq!(transports[0].interleaved(), None);
+    }
+
+    #[test]
+    fn transport_parses_client_and_server_ports() {
+        let transports = RtspTransport::parse_header("RTP/AVP;unicast;client_port=5000-5001;server_port=6000-6001").unwrap();
+        assert!(!transports[0].is_tcp());
+        assert_eq!(transports[0].client_port(), Some((5000, 5001)));
+        assert_eq!(transports[0].server_port(), Some((6000, 6001)));
+        assert_eq!(transports[0].interleaved(), None);
+    }
+
+    #[test]
+    fn transport_bare_port_implies_the_next_one() {
+        let transports = RtspTransport::parse_header("RTP/AVP/UDP;unicast;client_port=5000").unwrap();
+        assert_eq!(transports[0].client_port(), Some((5000, 5001)));
+
+        let transports = RtspTransport::parse_header("RTP/AVP/TCP;interleaved=4").unwrap();
+        assert_eq!(transports[0].interleaved(), Some((4, 5)));
+
+        let transports = RtspTransport::parse_header("RTP/AVP;client_port=65535").unwrap();
+        assert_eq!(transports[0].client_port(), None);
+
+        // values from the client that don't fit are ignored rather than overflowing
+        let transports = RtspTransport::parse_header("RTP/AVP/TCP;interleaved=4294967295").unwrap();
+        assert_eq!(transports[0].interleaved(), None);
+        let transports = RtspTransport::parse_header("RTP/AVP;client_port=4294967295").unwrap();
+        assert_eq!(transports[0].client_port(), None);
+        let transports = RtspTransport::parse_header("RTP/AVP;client_port=4294967296-1").unwrap();
+        assert_eq!(transports[0].client_port(), None);
+    }
+
+    #[test]
+    fn transport_header_round_trips() {
+        let header = "RTP/AVP/TCP;unicast;interleaved=0-1,RTP/AVP;unicast;client_port=5000-5001;mode=\"PLAY\"";
+        let mut transports = RtspTransport::parse_header(header).unwrap();
+        assert_eq!(transports.len(), 2);
+        assert_eq!(RtspTransport::to_header(&transports), header);
+
+        transports[1].set_param("CLIENT_PORT", Some("6000-6001"));
+        transports[1].remove_param("mode");
+        transports[1].set_param("ssrc", Some("1234ABCD"));
+        assert_eq!(transports[1].to_string(), "RTP/AVP;unicast;client_port=6000-6001;ssrc=1234ABCD");
+    }
+
+    #[test]
+    fn transport_rejects_other_protocols() {
+        assert!(RtspTransport::parse_header("").is_err());
+        assert!(RtspTransport::parse_header(" , ").is_err());
+        assert!(RtspTransport::parse_header("RTP/AVP;unicast,MP2T/H2221/UDP;unicast").is_err());
+    }
+}
@@ -0,0 +1,370 @@
+/*
+ * Copyright (c) 2022 Cisco and/or its affiliates.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at:
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::metrics::Metrics;
+use crate::rtsp::{RtspMethod, RtspRequest, RtspResponse, RtspTransport};
+
+use log::{debug, trace, warn};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// session timeout when the server doesn't give one (RFC 2326 section 12.37)
+const SESSION_DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// RTSP session state of a client flow (RFC 2326 appendix A)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    /// no SETUP yet, or torn down
+    Init,
+    /// set up, or paused
+    Ready,
+    Playing,
+    Recording,
+}
+
+impl SessionState {
+    /// label for the session metrics
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            SessionState::Init => "init",
+            SessionState::Ready => "ready",
+            SessionState::Playing => "playing",
+            SessionState::Recording => "recording",
+        }
+    }
+
+    /// state after a successful response to the method
+    /// the server has already accepted the request, so this follows the server rather than second-guessing it
+    fn next(self, method: &RtspMethod) -> SessionState {
+        match (self, method) {
+            (_, RtspMethod::Teardown) => SessionState::Init,
+            (SessionState::Init, RtspMethod::Setup) => SessionState::Ready,
+            (_, RtspMethod::Play) => SessionState::Playing,
+            (_, RtspMethod::Record) => SessionState::Recording,
+            (SessionState::Playing, RtspMethod::Pause) | (SessionState::Recording, RtspMethod::Pause) => SessionState::Ready,
+            (state, _) => state,
+        }
+    }
+}
+
+impl fmt::Display for SessionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// When the client was last heard from, by an RTSP message or an RTCP report
+///
+/// Shared between the flow's reader and its media tasks, so it is touched without taking a lock.
+#[derive(Debug)]
+pub struct Keepalive {
+    start: Instant,
+    // milliseconds after start
+    last_seen: AtomicU64,
+}
+
+impl Keepalive {
+    pub fn new() -> Self {
+        Keepalive { start: Instant::now(), last_seen: AtomicU64::new(0) }
+    }
+
+    /// the client has just been heard from
+    pub fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last_seen.fetch_max(now, Ordering::Relaxed);
+    }
+
+    /// how long since the client was last heard from
+    pub fn idle(&self) -> Duration {
+        self.start.elapsed().saturating_sub(Duration::from_millis(self.last_seen.load(Ordering::Relaxed)))
+    }
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Snapshot of a flow's RTSP session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionSummary {
+    pub state: SessionState,
+    /// session id given by the server, until the session is torn down
+    pub id: Option<String>,
+    /// transports the client negotiated in SETUP, as the client sees them
+    pub transports: Vec<RtspTransport>,
+}
+
+impl fmt::Display for SessionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.state, self.id.as_deref().unwrap_or("-"), RtspTransport::to_header(&self.transports))
+    }
+}
+
+/// RTSP session of one client flow, driven by the client's requests and the server's responses to them
+///
+/// Requests are noted by CSeq and only change the state once the CP answers them with a 2xx response,
+/// which also gives the session id and the transport negotiated for each SETUP.
+/// The flow is counted in the session metrics under its current state until it is dropped.
+/// The flow times out if the client goes quiet for longer than the session's timeout, or the default before the server gives one.
+#[derive(Debug)]
+pub struct RtspSession {
+    state: SessionState,
+    id: Option<String>,
+    timeout: Duration,
+    transports: Vec<RtspTransport>,
+    // methods of requests awaiting a response, keyed by CSeq
+    requests: HashMap<u32, RtspMethod>,
+    keepalive: Arc<Keepalive>,
+    metrics: Metrics,
+}
+
+impl RtspSession {
+    pub fn new(metrics: Metrics, keepalive: Arc<Keepalive>) -> Self {
+        metrics.rtsp_sessions.with_label_values(&[SessionState::Init.as_str()]).inc();
+        RtspSession {
+            state: SessionState::Init,
+            id: None,
+            timeout: SESSION_DEFAULT_TIMEOUT,
+            transports: Vec::new(),
+            requests: HashMap::new(),
+            keepalive,
+            metrics,
+        }
+    }
+
+    /// session id given by the server, until the session is torn down
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// how long the client may go without being heard from, the default until the server gives a session timeout
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// when the client was last heard from
+    pub fn keepalive(&self) -> &Arc<Keepalive> {
+        &self.keepalive
+    }
+
+    /// snapshot of the session for reporting
+    pub fn summary(&self) -> SessionSummary {
+        SessionSummary { state: self.state, id: self.id.clone(), transports: self.transports.clone() }
+    }
+
+    /// whether the client may send media: once PLAY or RECORD has been accepted, until PAUSE or TEARDOWN
+    pub fn media_allowed(&self) -> bool {
+        self.state == SessionState::Playing || self.state == SessionState::Recording
+    }
+
+    /// note a request from the client, so the response to it can be matched by CSeq
+    pub fn request(&mut self, request: &RtspRequest) {
+        match request.headers.cseq() {
+            Some(cseq) => { self.requests.insert(cseq, request.method.clone()); },
+            None => warn!("{} request without CSeq can't change the session", request.method),
+        }
+    }
+
+    /// apply a response from the server, returning the new state if it changed
+    pub fn response(&mut self, response: &RtspResponse) -> Option<SessionState> {
+        let method = match response.headers.cseq().and_then(|cseq| self.requests.remove(&cseq)) {
+            Some(method) => method,
+            None => return None,
+        };
+
+        if !(200..300).contains(&response.status) {
+            debug!("{} failed with status {}, session stays {}", method, response.status, self.state);
+            return None
+        }
+
+        match method {
+            RtspMethod::Teardown => {
+                self.id = None;
+                self.timeout = SESSION_DEFAULT_TIMEOUT;
+                self.transports.clear();
+            },
+            _ => {
+                if let Some(id) = response.headers.session_id() {
+                    self.id = Some(id.to_string());
+                    // a timeout of 0 would expire the session at once, so keep the default
+                    match response.headers.session_timeout() {
+                        Some(timeout) if timeout > 0 => self.timeout = Duration::from_secs(timeout),
+                        _ => trace!("session {} keeps timeout {:?}", id, self.timeout),
+                    }
+                }
+            },
+        }
+
+        if method == RtspMethod::Setup {
+            match response.headers.transport().map(RtspTransport::parse_header) {
+                Some(Ok(transports)) => self.transports.extend(transports),
+                Some(Err(e)) => warn!("unable to parse SETUP response Transport header: {}", e),
+                None => warn!("SETUP response without Transport header"),
+            }
+        }
+
+        let next = self.state.next(&method);
+        if next == self.state {
+            return None
+        }
+
+        debug!("RTSP session {} after {}", next, method);
+        self.metrics.rtsp_sessions.with_label_values(&[self.state.as_str()]).dec();
+        self.metrics.rtsp_sessions.with_label_values(&[next.as_str()]).inc();
+        self.state = next;
+        return Some(next)
+    }
+}
+
+impl Drop for RtspSession {
+    fn drop(&mut self) {
+        self.metrics.rtsp_sessions.with_label_values(&[self.state.as_str()]).dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtsp::RtspMessage;
+
+    fn request(method: &str, cseq: u32) -> RtspRequest {
+        let data = format!("{} rtsp://camera/stream RTSP/1.0\r\nCSeq: {}\r\n\r\n", method, cseq);
+        match RtspMessage::parse(data.as_bytes()) {
+            Ok(RtspMessage::Request(request)) => return request,
+            other => panic!("not a request: {:?}", other),
+        }
+    }
+
+    fn response(status: u16, cseq: u32, headers: &str) -> RtspResponse {
+        let data = format!("RTSP/1.0 {} Reason\r\nCSeq: {}\r\n{}\r\n", status, cseq, headers);
+        match RtspMessage::parse(data.as_bytes()) {
+            Ok(RtspMessage::Response(response)) => return response,
+            other => panic!("not a response: {:?}", other),
+        }
+    }
+
+    fn sessions(metrics: &Metrics, state: SessionState) -> i64 {
+        metrics.rtsp_sessions.with_label_values(&[state.as_str()]).get()
+    }
+
+    #[test]
+    fn next_follows_the_rfc_state_table() {
+        assert_eq!(SessionState::Init.next(&RtspMethod::Setup), SessionState::Ready);
+        assert_eq!(SessionState::Ready.next(&RtspMethod::Setup), SessionState::Ready);
+        assert_eq!(SessionState::Playing.next(&RtspMethod::Setup), SessionState::Playing);
+        assert_eq!(SessionState::Ready.next(&RtspMethod::Play), SessionState::Playing);
+        assert_eq!(SessionState::Ready.next(&RtspMethod::Record), SessionState::Recording);
+        assert_eq!(SessionState::Playing.next(&RtspMethod::Pause), SessionState::Ready);
+        assert_eq!(SessionState::Recording.next(&RtspMethod::Pause), SessionState::Ready);
+        assert_eq!(SessionState::Ready.next(&RtspMethod::Pause), SessionState::Ready);
+        assert_eq!(SessionState::Init.next(&RtspMethod::Pause), SessionState::Init);
+        assert_eq!(SessionState::Playing.next(&RtspMethod::Options), SessionState::Playing);
+        assert_eq!(SessionState::Init.next(&RtspMethod::Describe), SessionState::Init);
+        for state in [SessionState::Init, SessionState::Ready, SessionState::Playing, SessionState::Recording] {
+            assert_eq!(state.next(&RtspMethod::Teardown), SessionState::Init);
+        }
+    }
+
+    #[test]
+    fn responses_drive_the_session() {
+        let metrics = Metrics::new();
+        let mut session = RtspSession::new(metrics.clone(), Arc::new(Keepalive::new()));
+        assert_eq!(sessions(&metrics, SessionState::Init), 1);
+        assert_eq!(session.timeout(), SESSION_DEFAULT_TIMEOUT);
+
+        session.request(&request("SETUP", 1));
+        let state = session.response(&response(200, 1, "Session: 12345678;timeout=30\r\nTransport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n"));
+        assert_eq!(state, Some(SessionState::Ready));
+        assert_eq!(session.id(), Some("12345678"));
+        assert_eq!(session.timeout(), Duration::from_secs(30));
+        assert_eq!(session.summary().transports, RtspTransport::parse_header("RTP/AVP/TCP;unicast;interleaved=0-1").unwrap());
+        assert!(!session.media_allowed());
+
+        // a second SETUP adds its transport without changing the state
+        session.request(&request("SETUP", 2));
+        assert_eq!(session.response(&response(200, 2, "Session: 12345678\r\nTransport: RTP/AVP/TCP;unicast;interleaved=2-3\r\n")), None);
+        assert_eq!(session.summary().transports.len(), 2);
+        assert_eq!(session.timeout(), Duration::from_secs(30));
+
+        session.request(&request("PLAY", 3));
+        assert_eq!(session.response(&response(200, 3, "Session: 12345678\r\n")), Some(SessionState::Playing));
+        assert!(session.media_allowed());
+        assert_eq!(sessions(&metrics, SessionState::Playing), 1);
+        assert_eq!(sessions(&metrics, SessionState::Ready), 0);
+
+        session.request(&request("PAUSE", 4));
+        assert_eq!(session.response(&response(200, 4, "Session: 12345678\r\n")), Some(SessionState::Ready));
+        assert!(!session.media_allowed());
+
+        session.request(&request("TEARDOWN", 5));
+        assert_eq!(session.response(&response(200, 5, "")), Some(SessionState::Init));
+        assert_eq!(session.summary(), SessionSummary { state: SessionState::Init, id: None, transports: Vec::new() });
+        assert_eq!(session.timeout(), SESSION_DEFAULT_TIMEOUT);
+
+        drop(session);
+        assert_eq!(sessions(&metrics, SessionState::Init), 0);
+    }
+
+    #[test]
+    fn failed_and_unknown_responses_are_ignored() {
+        let metrics = Metrics::new();
+        let mut session = RtspSession::new(metrics, Arc::new(Keepalive::new()));
+
+        session.request(&request("SETUP", 1));
+        assert_eq!(session.response(&response(461, 1, "Session: 12345678\r\n")), None);
+        assert_eq!(session.id(), None);
+
+        // the failed request is forgotten, so a late success with its CSeq is not applied
+        assert_eq!(session.response(&response(200, 1, "Session: 12345678\r\n")), None);
+        assert_eq!(session.response(&response(200, 7, "Session: 12345678\r\n")), None);
+        assert_eq!(session.summary().state, SessionState::Init);
+        assert_eq!(session.id(), None);
+    }
+
+    #[test]
+    fn zero_timeout_keeps_the_default() {
+        let mut session = RtspSession::new(Metrics::new(), Arc::new(Keepalive::new()));
+
+        session.request(&request("SETUP", 1));
+        session.response(&response(200, 1, "Session: abc;timeout=0\r\nTransport: RTP/AVP;unicast;client_port=5000-5001\r\n"));
+        assert_eq!(session.id(), Some("abc"));
+        assert_eq!(session.timeout(), SESSION_DEFAULT_TIMEOUT);
+        assert_eq!(session.summary().to_string(), "ready abc RTP/AVP;unicast;client_port=5000-5001");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keepalive_measures_idle_from_the_last_touch() {
+        let keepalive = Keepalive::new();
+        assert_eq!(keepalive.idle(), Duration::ZERO);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(keepalive.idle(), Duration::from_secs(10));
+
+        keepalive.touch();
+        assert_eq!(keepalive.idle(), Duration::ZERO);
+
+        tokio::time::advance(Duration::from_millis(2500)).await;
+        assert_eq!(keepalive.idle(), Duration::from_millis(2500));
+    }
+}
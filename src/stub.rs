@@ -0,0 +1,165 @@
+/*
+ * Copyright (c) 2022 Cisco and/or its affiliates.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at:
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::cp::{ControlPlane, FlowKey};
+use crate::dp::DataPlane;
+use crate::lock::lock;
+use crate::metrics::Metrics;
+use crate::session::{RtspSession, SessionSummary};
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{watch, Notify};
+
+/// Runtime state for one stub instance
+///
+/// Owns the CP channel, the flow table and the DP sockets, so several stubs can run in one process.
+/// Share it between the client listener, the CP connector and their tasks as an `Arc<Stub>`.
+#[derive(Debug)]
+pub struct Stub {
+    pub(crate) cp: ControlPlane,
+    pub(crate) dp: DataPlane,
+    pub(crate) metrics: Metrics,
+    shutdown: watch::Sender<bool>,
+    listening: AtomicBool,
+    flows: AtomicUsize,
+    next_flow_id: AtomicU64,
+    flows_closed: Notify,
+    // RTSP sessions of the live flows, for reporting
+    sessions: Mutex<HashMap<FlowKey, Arc<Mutex<RtspSession>>>>,
+}
+
+/// Counts a client flow as live until dropped
+#[derive(Debug)]
+pub(crate) struct FlowGuard {
+    stub: Arc<Stub>,
+    id: u64,
+}
+
+impl FlowGuard {
+    /// identifies the flow in logs, unique for the life of the stub
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+/// Keeps a flow's RTSP session in the stub's sessions until dropped
+#[derive(Debug)]
+pub(crate) struct SessionGuard {
+    stub: Arc<Stub>,
+    flow: FlowKey,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        lock(&self.stub.sessions).remove(&self.flow);
+    }
+}
+
+impl Drop for FlowGuard {
+    fn drop(&mut self) {
+        self.stub.metrics.active_flows.dec();
+        if self.stub.flows.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.stub.flows_closed.notify_waiters();
+        }
+    }
+}
+
+impl Stub {
+    /// local_rtp_port is the first of the local ports used for RTP/RTCP towards the DP
+    pub fn new(local_rtp_port: u16) -> Arc<Self> {
+        let (shutdown, _) = watch::channel(false);
+        Arc::new(Stub {
+            cp: ControlPlane::new(),
+            dp: DataPlane::new(local_rtp_port),
+            metrics: Metrics::new(),
+            shutdown,
+            listening: AtomicBool::new(false),
+            flows: AtomicUsize::new(0),
+            next_flow_id: AtomicU64::new(1),
+            flows_closed: Notify::new(),
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Stop accepting clients and close every live flow
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Completes once shutdown has been requested
+    pub async fn shutdown_requested(&self) {
+        let mut shutdown = self.shutdown.subscribe();
+        while !*shutdown.borrow_and_update() {
+            if shutdown.changed().await.is_err() {
+                return
+            }
+        }
+    }
+
+    /// Whether the client listener is accepting connections
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_listening(&self, listening: bool) {
+        self.listening.store(listening, Ordering::SeqCst);
+    }
+
+    /// Number of client flows still open
+    pub fn active_flows(&self) -> usize {
+        self.flows.load(Ordering::SeqCst)
+    }
+
+    /// Completes once every client flow has closed
+    pub async fn drained(&self) {
+        loop {
+            let closed = self.flows_closed.notified();
+            if self.active_flows() == 0 {
+                return
+            }
+            closed.await;
+        }
+    }
+
+    /// Prometheus metrics for this stub
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// RTSP session of each client flow still open
+    pub fn sessions(&self) -> Vec<(FlowKey, SessionSummary)> {
+        let sessions = lock(&self.sessions);
+        return sessions.iter().map(|(flow, session)| (flow.clone(), lock(session).summary())).collect()
+    }
+
+    pub(crate) fn session_guard(self: &Arc<Self>, flow: &FlowKey, session: Arc<Mutex<RtspSession>>) -> SessionGuard {
+        lock(&self.sessions).insert(flow.clone(), session);
+        SessionGuard { stub: self.clone(), flow: flow.clone() }
+    }
+
+    pub(crate) fn flow_guard(self: &Arc<Self>) -> FlowGuard {
+        self.flows.fetch_add(1, Ordering::SeqCst);
+        self.metrics.active_flows.inc();
+        FlowGuard { stub: self.clone(), id: self.next_flow_id.fetch_add(1, Ordering::SeqCst) }
+    }
+}
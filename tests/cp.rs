@@ -0,0 +1,400 @@
+/*
+ * Copyright (c) 2022 Cisco and/or its affiliates.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at:
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use msm_rtsp_stub::admin::admin_server;
+use msm_rtsp_stub::client::client_listener;
+use msm_rtsp_stub::cp::msm_cp::msm_control_plane_server::{MsmControlPlane, MsmControlPlaneServer};
+use msm_rtsp_stub::cp::msm_cp::{DataPlaneConfig, Event, Flow, Message};
+use msm_rtsp_stub::cp::{cp_add, cp_connector, cp_data, cp_delete, FlowHandle, FlowKey};
+use msm_rtsp_stub::stub::Stub;
+
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+use tonic::codegen::futures_core::Stream;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+
+/// how long a test waits for the stub to send the next message
+const WAIT: Duration = Duration::from_secs(5);
+
+type CpStream = Pin<Box<dyn Stream<Item = Result<Message, Status>> + Send + 'static>>;
+type CpSender = mpsc::UnboundedSender<Result<Message, Status>>;
+
+/// Control plane that passes on every message it receives, tagged with the stream it came in on
+/// and lets the test end any stream
+#[derive(Debug, Clone)]
+struct TestCp {
+    received: mpsc::UnboundedSender<(usize, Message)>,
+    // senders of the open streams back to the stub, by stream number from 1
+    streams: Arc<Mutex<HashMap<usize, CpSender>>>,
+    opened: Arc<AtomicUsize>,
+}
+
+impl TestCp {
+    /// end a stream, as a CP restarting would
+    fn end_stream(&self, stream: usize) {
+        self.streams.lock().unwrap().remove(&stream).expect("no such stream");
+    }
+}
+
+#[tonic::async_trait]
+impl MsmControlPlane for TestCp {
+    type SendStream = CpStream;
+
+    async fn send(&self, request: Request<Streaming<Message>>) -> Result<Response<CpStream>, Status> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let stream = self.opened.fetch_add(1, Ordering::SeqCst) + 1;
+        self.streams.lock().unwrap().insert(stream, tx);
+
+        let received = self.received.clone();
+        let mut inbound = request.into_inner();
+        tokio::spawn(async move {
+            while let Ok(Some(message)) = inbound.message().await {
+                if received.send((stream, message)).is_err() {
+                    return
+                }
+            }
+        });
+
+        let outbound = async_stream::stream! {
+            while let Some(message) = rx.recv().await {
+                yield message
+            }
+        };
+        return Ok(Response::new(Box::pin(outbound)))
+    }
+}
+
+/// a local port nothing else is listening on
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").and_then(|listener| listener.local_addr()).map(|address| address.port()).expect("no free port")
+}
+
+/// serve the test CP on a local port
+fn serve(cp: &TestCp, port: u16) -> JoinHandle<std::result::Result<(), tonic::transport::Error>> {
+    return tokio::spawn(Server::builder()
+        .add_service(MsmControlPlaneServer::new(cp.clone()))
+        .serve(([127, 0, 0, 1], port).into()))
+}
+
+/// next message from the stub, with the stream it came in on
+async fn next(received: &mut mpsc::UnboundedReceiver<(usize, Message)>) -> (usize, Option<Event>, Message) {
+    let (stream, message) = timeout(WAIT, received.recv()).await.expect("nothing from stub").expect("CP stopped");
+    return (stream, Event::from_i32(message.event), message)
+}
+
+/// handle for a flow, with the receiver the CP closes it through
+fn flow_handle(rtp_port: Option<u16>) -> (FlowHandle, oneshot::Receiver<Option<Vec<u8>>>) {
+    let (tx, _) = mpsc::channel(1);
+    let (close, closed) = oneshot::channel();
+    return (FlowHandle { tx, close, rtp_port }, closed)
+}
+
+#[tokio::test]
+async fn reconnect_registers_and_replays_live_flows() {
+    let (received_tx, mut received) = mpsc::unbounded_channel();
+    let cp = TestCp { received: received_tx, streams: Arc::default(), opened: Arc::default() };
+
+    let port = free_port();
+    let server = serve(&cp, port);
+
+    let stub = Stub::new(free_port());
+    let connector = tokio::spawn(cp_connector(stub.clone(), format!("http://127.0.0.1:{}", port).parse().unwrap()));
+
+    let (stream, event, _) = next(&mut received).await;
+    assert_eq!((stream, event), (1, Some(Event::Register)));
+
+    // one flow that stays and one that goes before the CP restarts
+    let live = FlowKey::new("127.0.0.1:8554".to_string(), "127.0.0.1:50000".to_string());
+    let gone = FlowKey::new("127.0.0.1:8554".to_string(), "127.0.0.1:50001".to_string());
+    cp_add(&stub, flow_handle(Some(5000)).0, &live).await.expect("ADD not sent");
+    cp_add(&stub, flow_handle(None).0, &gone).await.expect("ADD not sent");
+    for _ in 0..2 {
+        let (stream, event, _) = next(&mut received).await;
+        assert_eq!((stream, event), (1, Some(Event::Add)));
+    }
+    cp_delete(&stub, &gone).await.expect("DELETE not sent");
+    let (stream, event, _) = next(&mut received).await;
+    assert_eq!((stream, event), (1, Some(Event::Delete)));
+
+    // the stub registers again on a new stream and announces the flow still open, with its port
+    cp.end_stream(1);
+    let (stream, event, _) = next(&mut received).await;
+    assert_eq!((stream, event), (2, Some(Event::Register)));
+    let (stream, event, message) = next(&mut received).await;
+    assert_eq!((stream, event), (2, Some(Event::Add)));
+    let flow = message.flow.expect("ADD without flow");
+    assert_eq!((flow.local.as_str(), flow.remote.as_str(), flow.rtp_port), ("127.0.0.1:8554", "127.0.0.1:50000", 5000));
+    assert_eq!(message.data, "5000");
+
+    // and nothing for the deleted flow comes before the flow's next message
+    cp_data(&stub, &live, b"OPTIONS * RTSP/1.0\r\nCSeq: 1\r\n\r\n".to_vec(), None).await.expect("DATA not sent");
+    let (stream, event, message) = next(&mut received).await;
+    assert_eq!((stream, event), (2, Some(Event::Data)));
+    assert_eq!(message.flow.map(|flow| flow.remote), Some(live.remote.clone()));
+
+    let metrics = String::from_utf8(stub.metrics().encode().unwrap()).unwrap();
+    assert!(metrics.lines().any(|line| line == "msm_rtsp_stub_cp_reconnects_total 1"), "{}", metrics);
+
+    connector.abort();
+    server.abort();
+}
+
+/// wait for a metric line, as the stub updates it
+async fn metric(stub: &Stub, line: &str) {
+    timeout(WAIT, async {
+        while !String::from_utf8(stub.metrics().encode().unwrap()).unwrap().lines().any(|metric| metric == line) {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap_or_else(|_| panic!("no {}", line));
+}
+
+/// start the stub's client listener and connect a client to it
+async fn client(stub: &Arc<Stub>) -> (TcpStream, JoinHandle<std::io::Result<()>>) {
+    let rtsp_port = free_port();
+    let listener_stub = stub.clone();
+    let listener = tokio::spawn(async move { client_listener(listener_stub, format!("127.0.0.1:{}", rtsp_port)).await });
+    let client = timeout(WAIT, async {
+        loop {
+            match TcpStream::connect(("127.0.0.1", rtsp_port)).await {
+                Ok(client) => return client,
+                Err(_) => sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }).await.expect("stub not listening");
+    return (client, listener)
+}
+
+/// read one RTSP message from the stub
+async fn response(client: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8];
+        timeout(WAIT, client.read_exact(&mut byte)).await.expect("no response from stub").expect("client closed");
+        response.push(byte[0]);
+    }
+    return String::from_utf8(response).expect("response not UTF-8")
+}
+
+#[tokio::test]
+async fn outage_answers_requests_with_503_and_keeps_the_flow() {
+    let (received_tx, mut received) = mpsc::unbounded_channel();
+    let cp = TestCp { received: received_tx, streams: Arc::default(), opened: Arc::default() };
+
+    let port = free_port();
+    let server = serve(&cp, port);
+
+    let stub = Stub::new(free_port());
+    let connector = tokio::spawn(cp_connector(stub.clone(), format!("http://127.0.0.1:{}", port).parse().unwrap()));
+    let (stream, event, _) = next(&mut received).await;
+    assert_eq!((stream, event), (1, Some(Event::Register)));
+
+    let (mut client, listener) = client(&stub).await;
+    let (stream, event, message) = next(&mut received).await;
+    assert_eq!((stream, event), (1, Some(Event::Add)));
+    let remote = message.flow.expect("ADD without flow").remote;
+
+    // the CP goes away and the stub can't reach it again
+    server.abort();
+    cp.end_stream(1);
+    metric(&stub, "msm_rtsp_stub_cp_reconnects_total 1").await;
+
+    // so the stub answers the client itself, keeping the flow
+    for cseq in 1..=2 {
+        client.write_all(format!("OPTIONS * RTSP/1.0\r\nCSeq: {}\r\n\r\n", cseq).as_bytes()).await.expect("client write failed");
+        let response = response(&mut client).await;
+        assert!(response.starts_with("RTSP/1.0 503 Service Unavailable\r\n"), "{}", response);
+        assert!(response.contains(&format!("CSeq: {}\r\n", cseq)), "{}", response);
+    }
+    assert_eq!(stub.active_flows(), 1);
+
+    // and announces the flow once the CP is back
+    let server = serve(&cp, port);
+    let (stream, event, _) = next(&mut received).await;
+    assert_eq!((stream, event), (2, Some(Event::Register)));
+    let (stream, event, message) = next(&mut received).await;
+    assert_eq!((stream, event), (2, Some(Event::Add)));
+    assert_eq!(message.flow.map(|flow| flow.remote), Some(remote));
+
+    stub.shutdown();
+    listener.abort();
+    connector.abort();
+    server.abort();
+}
+
+#[tokio::test]
+async fn setup_before_dp_config_is_answered_with_503() {
+    let (received_tx, mut received) = mpsc::unbounded_channel();
+    let cp = TestCp { received: received_tx, streams: Arc::default(), opened: Arc::default() };
+
+    let port = free_port();
+    let server = serve(&cp, port);
+
+    // the CP never sends CONFIG, so the flow has no DP sockets
+    let stub = Stub::new(free_port());
+    let connector = tokio::spawn(cp_connector(stub.clone(), format!("http://127.0.0.1:{}", port).parse().unwrap()));
+    let (stream, event, _) = next(&mut received).await;
+    assert_eq!((stream, event), (1, Some(Event::Register)));
+
+    let (mut client, listener) = client(&stub).await;
+    let (_, event, message) = next(&mut received).await;
+    assert_eq!(event, Some(Event::Add));
+    assert_eq!(message.flow.map(|flow| flow.rtp_port), Some(0));
+
+    // the stub answers the SETUP itself, as the CP would set up media the flow can't carry
+    client.write_all(b"SETUP rtsp://camera/stream RTSP/1.0\r\nCSeq: 2\r\nTransport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n\r\n").await.expect("client write failed");
+    let response = response(&mut client).await;
+    assert!(response.starts_with("RTSP/1.0 503 Service Unavailable\r\n"), "{}", response);
+    assert!(response.contains("CSeq: 2\r\n"), "{}", response);
+
+    // while other requests still go to the CP, the SETUP first had it been passed on
+    client.write_all(b"OPTIONS * RTSP/1.0\r\nCSeq: 3\r\n\r\n").await.expect("client write failed");
+    let (_, event, message) = next(&mut received).await;
+    assert_eq!(event, Some(Event::Data));
+    assert!(message.payload.starts_with(b"OPTIONS "), "{}", String::from_utf8_lossy(&message.payload));
+
+    stub.shutdown();
+    listener.abort();
+    connector.abort();
+    server.abort();
+}
+
+/// status and body of a GET from the stub's admin server
+async fn admin(port: u16, path: &str) -> (u16, String) {
+    let mut connection = TcpStream::connect(("127.0.0.1", port)).await.expect("admin server not listening");
+    connection.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).as_bytes()).await.expect("admin write failed");
+    let mut response = String::new();
+    timeout(WAIT, connection.read_to_string(&mut response)).await.expect("no admin response").expect("admin read failed");
+
+    let status = response.split(' ').nth(1).and_then(|status| status.parse().ok()).expect("no admin status");
+    let body = response.split_once("\r\n\r\n").map(|(_, body)| body.to_string()).unwrap_or_default();
+    return (status, body)
+}
+
+/// wait for an admin path to give the status
+async fn admin_status(port: u16, path: &str, status: u16) -> String {
+    return timeout(WAIT, async {
+        loop {
+            let (current, body) = admin(port, path).await;
+            if current == status {
+                return body
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap_or_else(|_| panic!("{} never {}", path, status))
+}
+
+#[tokio::test]
+async fn ready_once_registered_and_configured_until_shutdown() {
+    let (received_tx, mut received) = mpsc::unbounded_channel();
+    let cp = TestCp { received: received_tx, streams: Arc::default(), opened: Arc::default() };
+
+    let port = free_port();
+    let server = serve(&cp, port);
+
+    let stub = Stub::new(free_port());
+    let admin_port = free_port();
+    let admin_handle = tokio::spawn(admin_server(stub.clone(), ([127, 0, 0, 1], admin_port).into()));
+    let (_client, listener) = client(&stub).await;
+    assert_eq!(admin_status(admin_port, "/readyz", 503).await, "running: ok\nlistener: ok\ncp registered: failed\ndp configured: failed\n");
+
+    // REGISTER alone isn't enough
+    let connector = tokio::spawn(cp_connector(stub.clone(), format!("http://127.0.0.1:{}", port).parse().unwrap()));
+    let (stream, event, _) = next(&mut received).await;
+    assert_eq!((stream, event), (1, Some(Event::Register)));
+    timeout(WAIT, async {
+        while admin(admin_port, "/readyz").await.1 != "running: ok\nlistener: ok\ncp registered: ok\ndp configured: failed\n" {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("REGISTER not noticed");
+    assert_eq!(admin(admin_port, "/readyz").await.0, 503);
+
+    // the stub is ready once the CP has configured the DP
+    let config = DataPlaneConfig { rtp_address: "127.0.0.1".to_string(), rtp_port: 9, ..DataPlaneConfig::default() };
+    let sender = cp.streams.lock().unwrap().get(&1).cloned().expect("no stream");
+    sender.send(Ok(Message { event: Event::Config as i32, config: Some(config), ..Message::default() })).expect("CONFIG not sent");
+    assert_eq!(admin_status(admin_port, "/readyz", 200).await, "running: ok\nlistener: ok\ncp registered: ok\ndp configured: ok\n");
+
+    // and not once it starts shutting down, though it is still healthy
+    stub.shutdown();
+    assert!(admin_status(admin_port, "/readyz", 503).await.starts_with("running: failed\n"));
+    assert_eq!(admin(admin_port, "/healthz").await, (200, "listener: ok\ncp connector: ok\n".to_string()));
+
+    listener.abort();
+    connector.abort();
+    admin_handle.abort();
+    server.abort();
+}
+
+#[tokio::test]
+async fn cp_delete_closes_flows_keyed_either_way() {
+    let (received_tx, mut received) = mpsc::unbounded_channel();
+    let cp = TestCp { received: received_tx, streams: Arc::default(), opened: Arc::default() };
+
+    let port = free_port();
+    let server = serve(&cp, port);
+
+    let stub = Stub::new(free_port());
+    let connector = tokio::spawn(cp_connector(stub.clone(), format!("http://127.0.0.1:{}", port).parse().unwrap()));
+    let (stream, event, _) = next(&mut received).await;
+    assert_eq!((stream, event), (1, Some(Event::Register)));
+
+    let legacy = FlowKey::new("127.0.0.1:8554".to_string(), "127.0.0.1:50000".to_string());
+    let typed = FlowKey::new("127.0.0.1:8554".to_string(), "127.0.0.1:50001".to_string());
+    let (handle, legacy_closed) = flow_handle(None);
+    cp_add(&stub, handle, &legacy).await.expect("ADD not sent");
+    let (handle, typed_closed) = flow_handle(None);
+    cp_add(&stub, handle, &typed).await.expect("ADD not sent");
+    for _ in 0..2 {
+        assert_eq!(next(&mut received).await.1, Some(Event::Add));
+    }
+
+    // an older CP keys the DELETE by the address strings, a newer one by the typed flow
+    let sender = cp.streams.lock().unwrap().get(&1).cloned().expect("no stream");
+    sender.send(Ok(Message { event: Event::Delete as i32, local: legacy.local.clone(), remote: legacy.remote.clone(), ..Message::default() })).expect("DELETE not sent");
+    let teardown = b"TEARDOWN rtsp://camera/stream RTSP/1.0\r\nCSeq: 1\r\n\r\n".to_vec();
+    let flow = Flow { local: typed.local.clone(), remote: typed.remote.clone(), rtp_port: 0 };
+    sender.send(Ok(Message { event: Event::Delete as i32, flow: Some(flow), payload: teardown.clone(), ..Message::default() })).expect("DELETE not sent");
+
+    // both flows are closed, the second with the message for its client
+    assert_eq!(timeout(WAIT, legacy_closed).await.expect("legacy flow not closed").expect("legacy flow dropped"), None);
+    assert_eq!(timeout(WAIT, typed_closed).await.expect("typed flow not closed").expect("typed flow dropped"), Some(teardown));
+
+    // and gone from the flow table, so neither is announced again after a reconnect
+    drop(sender);
+    cp.end_stream(1);
+    let (stream, event, _) = next(&mut received).await;
+    assert_eq!((stream, event), (2, Some(Event::Register)));
+    let other = FlowKey::new("127.0.0.1:8554".to_string(), "127.0.0.1:50002".to_string());
+    cp_data(&stub, &other, b"OPTIONS * RTSP/1.0\r\nCSeq: 1\r\n\r\n".to_vec(), None).await.expect("DATA not sent");
+    let (stream, event, message) = next(&mut received).await;
+    assert_eq!((stream, event), (2, Some(Event::Data)));
+    assert_eq!(message.flow.map(|flow| flow.remote), Some(other.remote));
+
+    connector.abort();
+    server.abort();
+}
@@ -0,0 +1,84 @@
+/*
+ * Copyright (c) 2022 Cisco and/or its affiliates.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at:
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use msm_rtsp_stub::client::client_listener;
+use msm_rtsp_stub::stub::Stub;
+
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+/// how long a test waits for a stub to get to the expected state
+const WAIT: Duration = Duration::from_secs(5);
+
+/// a local port nothing else is listening on
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").and_then(|listener| listener.local_addr()).map(|address| address.port()).expect("no free port")
+}
+
+/// start a stub's client listener, returning its port
+async fn listen(stub: &Arc<Stub>) -> (u16, JoinHandle<()>) {
+    let port = free_port();
+    let listener_stub = stub.clone();
+    let handle = tokio::spawn(async move {
+        client_listener(listener_stub, format!("127.0.0.1:{}", port)).await.expect("listener failed");
+    });
+
+    timeout(WAIT, async {
+        while !stub.is_listening() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("stub not listening");
+
+    return (port, handle)
+}
+
+fn accepted(stub: &Stub) -> String {
+    let metrics = String::from_utf8(stub.metrics().encode().expect("metrics not encoded")).expect("metrics not UTF-8");
+    return metrics.lines().find(|line| line.starts_with("msm_rtsp_stub_accepted_connections_total")).unwrap_or_default().to_string()
+}
+
+#[tokio::test]
+async fn two_stubs_keep_their_own_state() {
+    let first = Stub::new(free_port());
+    let second = Stub::new(free_port());
+
+    let (first_port, first_listener) = listen(&first).await;
+    let (_, second_listener) = listen(&second).await;
+
+    // a client of the first stub is only counted there
+    let _client = TcpStream::connect(("127.0.0.1", first_port)).await.expect("unable to connect");
+    timeout(WAIT, async {
+        while accepted(&first) != "msm_rtsp_stub_accepted_connections_total 1" {
+            sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("connection not counted");
+    assert_eq!(accepted(&second), "msm_rtsp_stub_accepted_connections_total 0");
+
+    // shutting one stub down leaves the other running
+    first.shutdown();
+    timeout(WAIT, first_listener).await.expect("listener still running").expect("listener panicked");
+    assert!(first.is_shutting_down() && !first.is_listening());
+    assert!(!second.is_shutting_down() && second.is_listening());
+
+    second.shutdown();
+    timeout(WAIT, second_listener).await.expect("listener still running").expect("listener panicked");
+    assert!(!second.is_listening());
+}